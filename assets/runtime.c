@@ -0,0 +1,533 @@
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+// Runtime of the builtin types, linked with every program
+
+int string_len(const char *s) {
+    return s == NULL ? 0 : (int) strlen(s);
+}
+
+// Sign of the difference between two strings, null sorts before any other string
+int string_compare(const char *a, const char *b) {
+    if (a == NULL || b == NULL) {
+        return (a != NULL) - (b != NULL);
+    }
+    int diff = strcmp(a, b);
+    return (diff > 0) - (diff < 0);
+}
+
+char *string_slice(const char *s, int start, int end) {
+    int length = string_len(s);
+    if (start < 0 || start > end || end > length) {
+        fprintf(stderr, "slice [%d..%d] out of bounds for length %d\n", start, end, length);
+        abort();
+    }
+    char *slice = malloc(end - start + 1);
+    memcpy(slice, s + start, end - start);
+    slice[end - start] = '\0';
+    return slice;
+}
+
+// Header of a vec, the elements are stored back to back in `data`
+struct vec {
+    char *data;
+    long len;
+    long cap;
+};
+
+struct vec *vec_new(void) {
+    return calloc(1, sizeof(struct vec));
+}
+
+int vec_len(const struct vec *v) {
+    return v == NULL ? 0 : (int) v->len;
+}
+
+// The value is passed in a whole register, only its first `size` bytes are stored
+void vec_push(struct vec **v, long size, long value) {
+    if (*v == NULL) {
+        *v = vec_new();
+    }
+    struct vec *vec = *v;
+    if (vec->len == vec->cap) {
+        vec->cap = vec->cap == 0 ? 8 : vec->cap * 2;
+        vec->data = realloc(vec->data, vec->cap * size);
+    }
+    memcpy(vec->data + vec->len * size, &value, size);
+    vec->len++;
+}
+
+long vec_pop(struct vec *v, long size) {
+    if (v == NULL || v->len == 0) {
+        fprintf(stderr, "pop from an empty vec\n");
+        abort();
+    }
+    long value = 0;
+    v->len--;
+    memcpy(&value, v->data + v->len * size, size);
+    return value;
+}
+
+// Header of a map. Entries are kept dense, the keys first so a map is iterated like a vec of its keys,
+// and `slots` is an open addressing table of entry positions plus one, 0 when empty and -1 when removed
+struct map {
+    char *keys;
+    long len;
+    long cap;
+    char *values;
+    long *slots;
+    long nslots;
+    // Slots that are not empty, removed ones included
+    long used;
+    // 0 for string keys, which are compared by content
+    long key_size;
+    long value_size;
+};
+
+struct map *map_new(long key_size, long value_size) {
+    struct map *m = calloc(1, sizeof(struct map));
+    m->key_size = key_size;
+    m->value_size = value_size;
+    return m;
+}
+
+int map_len(const struct map *m) {
+    return m == NULL ? 0 : (int) m->len;
+}
+
+// Only the first `size` bytes of a value passed in a register are meaningful
+static long map_truncate(long value, long size) {
+    long truncated = 0;
+    memcpy(&truncated, &value, size);
+    return truncated;
+}
+
+static long map_stride(const struct map *m) {
+    return m->key_size == 0 ? (long) sizeof(char *) : m->key_size;
+}
+
+static long map_key_at(const struct map *m, long pos) {
+    long key = 0;
+    memcpy(&key, m->keys + pos * map_stride(m), map_stride(m));
+    return key;
+}
+
+static unsigned long map_hash(const struct map *m, long key) {
+    unsigned long hash = 14695981039346656037UL;
+    if (m->key_size == 0) {
+        for (const char *s = (const char *) key; s != NULL && *s; s++) {
+            hash = (hash ^ (unsigned char) *s) * 1099511628211UL;
+        }
+        return hash;
+    }
+    // splitmix64 finalizer
+    hash = (unsigned long) key;
+    hash = (hash ^ (hash >> 30)) * 0xbf58476d1ce4e5b9UL;
+    hash = (hash ^ (hash >> 27)) * 0x94d049bb133111ebUL;
+    return hash ^ (hash >> 31);
+}
+
+static int map_equal(const struct map *m, long a, long b) {
+    if (m->key_size == 0 && a != b) {
+        return a != 0 && b != 0 && strcmp((const char *) a, (const char *) b) == 0;
+    }
+    return a == b;
+}
+
+// Slot holding the key, or the first free slot of its probe sequence when it is missing
+static long map_find(const struct map *m, long key, int *found) {
+    long mask = m->nslots - 1;
+    long free = -1;
+    for (long slot = map_hash(m, key) & mask;; slot = (slot + 1) & mask) {
+        long entry = m->slots[slot];
+        if (entry == 0) {
+            *found = 0;
+            return free == -1 ? slot : free;
+        }
+        if (entry == -1) {
+            if (free == -1) {
+                free = slot;
+            }
+        } else if (map_equal(m, map_key_at(m, entry - 1), key)) {
+            *found = 1;
+            return slot;
+        }
+    }
+}
+
+static void map_rehash(struct map *m, long nslots) {
+    free(m->slots);
+    m->slots = calloc(nslots, sizeof(long));
+    m->nslots = nslots;
+    m->used = m->len;
+    for (long pos = 0; pos < m->len; pos++) {
+        int found;
+        m->slots[map_find(m, map_key_at(m, pos), &found)] = pos + 1;
+    }
+}
+
+void map_insert(struct map **map, long key_size, long value_size, long key, long value) {
+    if (*map == NULL) {
+        *map = map_new(key_size, value_size);
+    }
+    struct map *m = *map;
+    key = m->key_size == 0 ? key : map_truncate(key, m->key_size);
+    // Removed slots count as used, so probing always ends on an empty one
+    if ((m->used + 1) * 2 > m->nslots) {
+        map_rehash(m, m->len * 4 < m->nslots ? m->nslots : m->nslots == 0 ? 16 : m->nslots * 2);
+    }
+    int found;
+    long slot = map_find(m, key, &found);
+    if (found) {
+        memcpy(m->values + (m->slots[slot] - 1) * m->value_size, &value, m->value_size);
+        return;
+    }
+    if (m->len == m->cap) {
+        m->cap = m->cap == 0 ? 8 : m->cap * 2;
+        m->keys = realloc(m->keys, m->cap * map_stride(m));
+        m->values = realloc(m->values, m->cap * m->value_size);
+    }
+    memcpy(m->keys + m->len * map_stride(m), &key, map_stride(m));
+    memcpy(m->values + m->len * m->value_size, &value, m->value_size);
+    if (m->slots[slot] == 0) {
+        m->used++;
+    }
+    m->len++;
+    m->slots[slot] = m->len;
+}
+
+long map_get(const struct map *m, long key, long fallback) {
+    if (m == NULL || m->len == 0) {
+        return fallback;
+    }
+    int found;
+    long slot = map_find(m, m->key_size == 0 ? key : map_truncate(key, m->key_size), &found);
+    if (!found) {
+        return fallback;
+    }
+    long value = 0;
+    memcpy(&value, m->values + (m->slots[slot] - 1) * m->value_size, m->value_size);
+    return value;
+}
+
+int map_contains(const struct map *m, long key) {
+    if (m == NULL || m->len == 0) {
+        return 0;
+    }
+    int found;
+    map_find(m, m->key_size == 0 ? key : map_truncate(key, m->key_size), &found);
+    return found;
+}
+
+// The last entry takes the place of the removed one, so the entries stay dense
+void map_remove(struct map *m, long key) {
+    if (m == NULL || m->len == 0) {
+        return;
+    }
+    int found;
+    long slot = map_find(m, m->key_size == 0 ? key : map_truncate(key, m->key_size), &found);
+    if (!found) {
+        return;
+    }
+    long pos = m->slots[slot] - 1;
+    long last = m->len - 1;
+    m->slots[slot] = -1;
+    if (pos != last) {
+        long moved = map_find(m, map_key_at(m, last), &found);
+        m->slots[moved] = pos + 1;
+        memcpy(m->keys + pos * map_stride(m), m->keys + last * map_stride(m), map_stride(m));
+        memcpy(m->values + pos * m->value_size, m->values + last * m->value_size, m->value_size);
+    }
+    m->len--;
+}
+
+// Arbitrary precision integer, immutable once built. The magnitude is stored in base 2^32,
+// least significant limb first, and null stands for 0
+struct bigint {
+    long negative;
+    long len;
+    unsigned int limbs[];
+};
+
+static const struct bigint bigint_zero = {0, 0};
+
+static const struct bigint *bigint_value(const struct bigint *b) {
+    return b == NULL ? &bigint_zero : b;
+}
+
+static struct bigint *bigint_alloc(long len) {
+    struct bigint *b = calloc(1, sizeof(struct bigint) + len * sizeof(unsigned int));
+    b->len = len;
+    return b;
+}
+
+// Drops the leading zero limbs, zero is never negative
+static struct bigint *bigint_trim(struct bigint *b) {
+    while (b->len > 0 && b->limbs[b->len - 1] == 0) {
+        b->len--;
+    }
+    if (b->len == 0) {
+        b->negative = 0;
+    }
+    return b;
+}
+
+struct bigint *bigint_from_u64(unsigned long value) {
+    struct bigint *b = bigint_alloc(2);
+    b->limbs[0] = (unsigned int) value;
+    b->limbs[1] = (unsigned int) (value >> 32);
+    return bigint_trim(b);
+}
+
+struct bigint *bigint_from_i64(long value) {
+    struct bigint *b = bigint_from_u64(value < 0 ? -(unsigned long) value : (unsigned long) value);
+    b->negative = value < 0;
+    return b;
+}
+
+// Low 64 bits in two's complement, like a cast between C integers
+long bigint_to_i64(const struct bigint *b) {
+    b = bigint_value(b);
+    unsigned long value = 0;
+    for (long i = b->len < 2 ? b->len - 1 : 1; i >= 0; i--) {
+        value = (value << 32) | b->limbs[i];
+    }
+    return b->negative ? -(long) value : (long) value;
+}
+
+static int bigint_compare_magnitude(const struct bigint *a, const struct bigint *b) {
+    if (a->len != b->len) {
+        return a->len > b->len ? 1 : -1;
+    }
+    for (long i = a->len - 1; i >= 0; i--) {
+        if (a->limbs[i] != b->limbs[i]) {
+            return a->limbs[i] > b->limbs[i] ? 1 : -1;
+        }
+    }
+    return 0;
+}
+
+int bigint_compare(const struct bigint *a, const struct bigint *b) {
+    a = bigint_value(a);
+    b = bigint_value(b);
+    if (a->negative != b->negative) {
+        return a->negative ? -1 : 1;
+    }
+    int magnitude = bigint_compare_magnitude(a, b);
+    return a->negative ? -magnitude : magnitude;
+}
+
+static struct bigint *bigint_add_magnitude(const struct bigint *a, const struct bigint *b) {
+    if (a->len < b->len) {
+        const struct bigint *swap = a;
+        a = b;
+        b = swap;
+    }
+    struct bigint *sum = bigint_alloc(a->len + 1);
+    unsigned long carry = 0;
+    for (long i = 0; i < a->len; i++) {
+        carry += (unsigned long) a->limbs[i] + (i < b->len ? b->limbs[i] : 0);
+        sum->limbs[i] = (unsigned int) carry;
+        carry >>= 32;
+    }
+    sum->limbs[a->len] = (unsigned int) carry;
+    return bigint_trim(sum);
+}
+
+// |a| - |b|, with |a| >= |b|
+static struct bigint *bigint_sub_magnitude(const struct bigint *a, const struct bigint *b) {
+    struct bigint *diff = bigint_alloc(a->len);
+    long borrow = 0;
+    for (long i = 0; i < a->len; i++) {
+        long value = (long) a->limbs[i] - (i < b->len ? b->limbs[i] : 0) - borrow;
+        borrow = value < 0;
+        diff->limbs[i] = (unsigned int) (value + (borrow << 32));
+    }
+    return bigint_trim(diff);
+}
+
+// a + b, with the sign of b flipped when `negate` is set
+static struct bigint *bigint_add_signed(const struct bigint *a, const struct bigint *b, int negate) {
+    a = bigint_value(a);
+    b = bigint_value(b);
+    long b_negative = b->len > 0 && (b->negative != negate);
+    struct bigint *result;
+    if (a->negative == b_negative) {
+        result = bigint_add_magnitude(a, b);
+        result->negative = a->negative;
+    } else if (bigint_compare_magnitude(a, b) >= 0) {
+        result = bigint_sub_magnitude(a, b);
+        result->negative = a->negative;
+    } else {
+        result = bigint_sub_magnitude(b, a);
+        result->negative = b_negative;
+    }
+    return bigint_trim(result);
+}
+
+struct bigint *bigint_add(const struct bigint *a, const struct bigint *b) {
+    return bigint_add_signed(a, b, 0);
+}
+
+struct bigint *bigint_sub(const struct bigint *a, const struct bigint *b) {
+    return bigint_add_signed(a, b, 1);
+}
+
+struct bigint *bigint_neg(const struct bigint *a) {
+    return bigint_add_signed(NULL, a, 1);
+}
+
+struct bigint *bigint_mul(const struct bigint *a, const struct bigint *b) {
+    a = bigint_value(a);
+    b = bigint_value(b);
+    struct bigint *product = bigint_alloc(a->len + b->len);
+    for (long i = 0; i < a->len; i++) {
+        unsigned long carry = 0;
+        for (long j = 0; j < b->len; j++) {
+            carry += (unsigned long) a->limbs[i] * b->limbs[j] + product->limbs[i + j];
+            product->limbs[i + j] = (unsigned int) carry;
+            carry >>= 32;
+        }
+        product->limbs[i + b->len] = (unsigned int) carry;
+    }
+    product->negative = a->negative != b->negative;
+    return bigint_trim(product);
+}
+
+// Quotient and remainder of the magnitudes, one bit at a time
+static void bigint_divide_magnitude(const struct bigint *a, const struct bigint *b, struct bigint **quotient,
+                                    struct bigint **remainder) {
+    if (b->len == 0) {
+        fprintf(stderr, "bigint division by zero\n");
+        abort();
+    }
+    struct bigint *q = bigint_alloc(a->len);
+    // One more limb than the divisor, the shifted remainder can overflow it before the subtraction
+    struct bigint *r = bigint_alloc(b->len + 1);
+    for (long bit = a->len * 32 - 1; bit >= 0; bit--) {
+        for (long i = r->len - 1; i > 0; i--) {
+            r->limbs[i] = (r->limbs[i] << 1) | (r->limbs[i - 1] >> 31);
+        }
+        r->limbs[0] = (r->limbs[0] << 1) | ((a->limbs[bit / 32] >> (bit % 32)) & 1);
+        long len = r->len;
+        bigint_trim(r);
+        if (bigint_compare_magnitude(r, b) >= 0) {
+            struct bigint *diff = bigint_sub_magnitude(r, b);
+            memset(r->limbs, 0, len * sizeof(unsigned int));
+            memcpy(r->limbs, diff->limbs, diff->len * sizeof(unsigned int));
+            free(diff);
+            q->limbs[bit / 32] |= 1u << (bit % 32);
+        }
+        r->len = len;
+    }
+    *quotient = bigint_trim(q);
+    *remainder = bigint_trim(r);
+}
+
+// Rounds toward zero like C
+struct bigint *bigint_div(const struct bigint *a, const struct bigint *b) {
+    a = bigint_value(a);
+    b = bigint_value(b);
+    struct bigint *q, *r;
+    bigint_divide_magnitude(a, b, &q, &r);
+    free(r);
+    q->negative = a->negative != b->negative;
+    return bigint_trim(q);
+}
+
+// Takes the sign of the dividend like C
+struct bigint *bigint_mod(const struct bigint *a, const struct bigint *b) {
+    a = bigint_value(a);
+    b = bigint_value(b);
+    struct bigint *q, *r;
+    bigint_divide_magnitude(a, b, &q, &r);
+    free(q);
+    r->negative = a->negative;
+    return bigint_trim(r);
+}
+
+char *bigint_to_string(const struct bigint *b) {
+    b = bigint_value(b);
+    // Every limb takes less than 10 digits, plus the sign and the terminator
+    char *text = malloc(b->len * 10 + 3);
+    char *end = text + b->len * 10 + 2;
+    char *digit = end;
+    *digit = '\0';
+    struct bigint *rest = bigint_alloc(b->len);
+    memcpy(rest->limbs, b->limbs, b->len * sizeof(unsigned int));
+    do {
+        unsigned long remainder = 0;
+        for (long i = rest->len - 1; i >= 0; i--) {
+            unsigned long value = (remainder << 32) | rest->limbs[i];
+            rest->limbs[i] = (unsigned int) (value / 10);
+            remainder = value % 10;
+        }
+        *--digit = (char) ('0' + remainder);
+        bigint_trim(rest);
+    } while (rest->len > 0);
+    free(rest);
+    if (b->negative) {
+        *--digit = '-';
+    }
+    memmove(text, digit, end - digit + 1);
+    return text;
+}
+
+struct bigint *bigint_parse(const char *text) {
+    const char *digit = text == NULL ? "" : text;
+    int negative = *digit == '-';
+    if (*digit == '-' || *digit == '+') {
+        digit++;
+    }
+    if (*digit == '\0') {
+        fprintf(stderr, "invalid bigint: \"%s\"\n", text == NULL ? "" : text);
+        abort();
+    }
+    struct bigint *b = bigint_alloc(strlen(digit) / 9 + 1);
+    long len = b->len;
+    for (; *digit; digit++) {
+        if (*digit < '0' || *digit > '9') {
+            fprintf(stderr, "invalid bigint: \"%s\"\n", text);
+            abort();
+        }
+        unsigned long carry = *digit - '0';
+        for (long i = 0; i < len; i++) {
+            carry += (unsigned long) b->limbs[i] * 10;
+            b->limbs[i] = (unsigned int) carry;
+            carry >>= 32;
+        }
+    }
+    b->negative = negative;
+    return bigint_trim(b);
+}
+
+// Functions used as values point to a record starting with their code. A closure also keeps the variables
+// it captured after the header, the record is passed to its code in %r10
+struct closure {
+    void *code;
+    long captured;
+};
+
+struct closure *closure_new(void *code, long size) {
+    struct closure *c = malloc(size);
+    c->code = code;
+    c->captured = size - (long) sizeof(struct closure);
+    return c;
+}
+
+// C calls the code directly, without the record, so closures needing one can not be passed to it
+void *closure_code(const struct closure *c) {
+    if (c == NULL) {
+        return NULL;
+    }
+    if (c->captured > 0) {
+        fprintf(stderr, "cannot pass a closure with captured variables to a C function\n");
+        abort();
+    }
+    return c->code;
+}
+
+struct closure *closure_from_code(void *code) {
+    return code == NULL ? NULL : closure_new(code, sizeof(struct closure));
+}
@@ -1,3 +1,4 @@
+#include <stdio.h>
 #include <unistd.h>
 #include <stdlib.h>
 #include <string.h>
@@ -20,3 +21,8 @@ char* read_until_eof(int fd) {
     buffer[used] = '\0';
     return buffer;
 }
+
+void out_of_bounds(const char *location, long index, long length) {
+    fprintf(stderr, "%s\nindex %ld out of bounds for length %ld\n", location, index, length);
+    abort();
+}
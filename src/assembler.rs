@@ -1,55 +1,120 @@
 #![allow(dead_code)]
 
 use crate::lexer::Rule;
-use crate::syntax::{Function, Parameter, Statement, Syntax, VarTree, VarType, Variable};
+use crate::syntax::{
+    Block, CLOSURE_ENV, Case, Closure, Expression, FOR_CURSOR, FOR_INDEX, FnCall, ForLoop, Function, If, Loop, Parameter, Statement,
+    Syntax, VarTree, VarType, Variable, While,
+};
 
 pub struct Assembler<'a> {
     syntax: Syntax<'a>,
     asm: Vec<String>,
+    rodata: Vec<String>,
+    next_label: usize,
+    /// Values pushed on the stack since the prologue, used to keep calls aligned
+    pushed: usize,
+    /// Enclosing loops as (label, continue target, break target)
+    loops: Vec<(Option<String>, String, String)>,
+    /// Check array indexes at runtime, reporting the source line before aborting
+    bounds_check: bool,
+    /// Bytes of the frame of the current function, the variables followed by the temporaries
+    frame: usize,
+    /// Slot keeping the address where a struct returned in memory is copied, received as a hidden first argument
+    return_buffer: Option<String>,
+    /// Slot keeping the environment record of the closure being generated, received in %r10
+    env: Option<String>,
+    /// Functions used as values, each one gets a record holding its address
+    records: Vec<String>,
 }
 
 struct Register<'a> {
     x64: &'a str,
     x32: &'a str,
     x16: &'a str,
+    x8: &'a str,
 }
 
+/// Register holding an eightbyte of an argument, by its index among the general purpose or the xmm registers
+#[derive(Clone, Copy)]
+enum ArgRegister {
+    Integer(usize),
+    Sse(usize),
+}
+
+/// Number of xmm registers used to pass arguments
+const SSE_REGS: usize = 8;
+
+/// Registers returning the integer eightbytes of a struct, the float ones use %xmm0 and %xmm1
+const RETURN_REGS: [&str; 2] = ["rax", "rdx"];
+
+const RAX: Register = Register {
+    x64: "rax",
+    x32: "eax",
+    x16: "ax",
+    x8: "al",
+};
+
+const RCX: Register = Register {
+    x64: "rcx",
+    x32: "ecx",
+    x16: "cx",
+    x8: "cl",
+};
+
 const REGS: [Register; 6] = [
     Register {
         x64: "rdi",
         x32: "edi",
         x16: "di",
+        x8: "dil",
     },
     Register {
         x64: "rsi",
         x32: "esi",
         x16: "si",
+        x8: "sil",
     },
     Register {
         x64: "rdx",
         x32: "edx",
         x16: "dx",
+        x8: "dl",
     },
     Register {
         x64: "rcx",
         x32: "ecx",
         x16: "cx",
+        x8: "cl",
     },
     Register {
         x64: "r8",
         x32: "r8d",
         x16: "r8w",
+        x8: "r8b",
     },
     Register {
         x64: "r9",
         x32: "r9d",
         x16: "r9w",
+        x8: "r9b",
     },
 ];
 
 impl<'a> Assembler<'a> {
-    pub fn new(syntax: Syntax<'a>) -> Assembler<'a> {
-        Assembler { syntax, asm: Vec::new() }
+    pub fn new(syntax: Syntax<'a>, bounds_check: bool) -> Assembler<'a> {
+        Assembler {
+            syntax,
+            asm: Vec::new(),
+            rodata: Vec::new(),
+            next_label: 0,
+            pushed: 0,
+            loops: Vec::new(),
+            bounds_check,
+            frame: 0,
+            return_buffer: None,
+            env: None,
+            records: Vec::new(),
+        }
     }
 
     pub fn assemble(&mut self) -> Result<String, Box<dyn std::error::Error>> {
@@ -67,9 +132,9 @@ impl<'a> Assembler<'a> {
         }
         self.push_asm("");
 
-        let mut main = self.syntax.functions.remove("main").unwrap();
+        let main = self.syntax.functions.remove("main").unwrap();
         self.push_asm(".text\n.globl main");
-        self.asm_function(&mut main)?;
+        self.asm_function(&main)?;
 
         // Collect all function names first to avoid the borrow checker error
         let function_names: Vec<String> = self.syntax.functions.keys().cloned().collect();
@@ -85,23 +150,111 @@ impl<'a> Assembler<'a> {
             self.push_asm("");
         }
 
+        self.asm_globals();
+        self.asm_records();
+
+        if !self.rodata.is_empty() {
+            self.push_asm(".section	.rodata");
+            self.push_asm(".align 8");
+            let rodata = std::mem::take(&mut self.rodata);
+            self.asm.extend(rodata);
+        }
+
         Ok(self.asm.join("\n"))
     }
 
+    /// Initialized globals go to .data, the others to .bss
+    fn asm_globals(&mut self) {
+        let globals = self.syntax.variables.variables.clone();
+        let (data, bss): (Vec<Variable>, Vec<Variable>) = globals
+            .into_iter()
+            .partition(|var| self.syntax.initializers.contains_key(&var.name));
+        if !data.is_empty() {
+            self.push_asm(".data");
+            for var in &data {
+                let value = self.syntax.initializers[&var.name].clone();
+                let directive = match (&var.var_type, &value) {
+                    (VarType::String, Expression::Value(param)) if param.id.is_some() => format!("  .quad .STR{}", param.id.unwrap()),
+                    (VarType::Float | VarType::U64, Expression::Value(param)) => format!("  .quad {}", param.value),
+                    (_, Expression::FunctionAddress(name, _)) => format!("  .quad {}", self.record(name)),
+                    (var_type, _) => {
+                        let directive = match var_type.size() {
+                            1 => ".byte",
+                            2 => ".short",
+                            4 => ".long",
+                            _ => ".quad",
+                        };
+                        format!("  {} {}", directive, value.constant().unwrap())
+                    }
+                };
+                self.push_asm(format!(".align {}", var.var_type.align()));
+                self.push_asm(format!("{}:", Self::global_symbol(&var.name)));
+                self.push_asm(directive);
+            }
+            self.push_asm("");
+        }
+        if !bss.is_empty() {
+            self.push_asm(".bss");
+            for var in &bss {
+                self.push_asm(format!(".align {}", var.var_type.align()));
+                self.push_asm(format!("{}:", Self::global_symbol(&var.name)));
+                self.push_asm(format!("  .zero {}", var.var_type.size()));
+            }
+            self.push_asm("");
+        }
+    }
+
+    /// Functions used as values are pointers to a record starting with their address, like closures are.
+    /// Their captures are empty, so C can be given the address itself
+    fn asm_records(&mut self) {
+        if self.records.is_empty() {
+            return;
+        }
+        self.push_asm(".data");
+        for name in std::mem::take(&mut self.records) {
+            self.push_asm(".align 8");
+            self.push_asm(format!(".FN_{}:", name));
+            self.push_asm(format!("  .quad {}", name));
+            self.push_asm("  .quad 0");
+        }
+        self.push_asm("");
+    }
+
+    /// Label of the record of a function used as a value
+    fn record(&mut self, name: &str) -> String {
+        if !self.records.iter().any(|n| n == name) {
+            self.records.push(name.to_string());
+        }
+        format!(".FN_{}", name)
+    }
+
+    /// Globals are not exported, the prefix keeps them apart from function names
+    fn global_symbol(name: &str) -> String {
+        format!(".G_{}", name)
+    }
+
     fn calc_stack(vt: &mut VarTree, start: usize) -> usize {
         let mut stack = start;
         for var in &mut vt.variables {
+            let size = match var.var_type {
+                // Whole eightbytes, so a struct passed in registers can be spilled with movq
+                VarType::Struct(_) => var.var_type.size().next_multiple_of(8),
+                _ => var.var_type.size(),
+            };
+            // The slot spans from -stack(%rbp) upwards, so its start must be aligned
+            stack = (stack + size).next_multiple_of(var.var_type.align());
             var.stack = Some(stack);
-            stack += 8;
         }
-        for (_, subtree) in &mut vt.children {
-            stack = Self::calc_stack(subtree, stack);
+        // Sibling scopes are never alive at the same time, so they share the same slots
+        let mut end = stack;
+        for subtree in vt.children.values_mut() {
+            end = end.max(Self::calc_stack(subtree, stack));
         }
-        stack
+        end
     }
 
     fn asm_function(&mut self, function: &Function) -> Result<(), Box<dyn std::error::Error>> {
-        let stack = {
+        self.frame = {
             let vt = self.syntax.variables.children.get_mut(&function.id).unwrap();
             vt.stack = Self::calc_stack(vt, 0);
             vt.stack
@@ -109,167 +262,1222 @@ impl<'a> Assembler<'a> {
         self.push_asm(format!("{}:", function.name));
         self.push_asm("  pushq %rbp");
         self.push_asm("  movq %rsp, %rbp");
-        if stack > 0 {
-            self.push_asm(format!("  subq ${}, %rsp", stack));
+        // The temporaries are only known once the body is generated, the frame size is filled in afterwards
+        let reserve = self.asm.len();
+        self.push_asm("");
+        self.return_buffer = match &function.return_type {
+            VarType::Struct(_) if Self::classify_return(&function.return_type).is_none() => {
+                let offset = self.temporary(&VarType::Pointer(Box::new(VarType::Void)));
+                Some(format!("-{}(%rbp)", offset))
+            }
+            _ => None,
+        };
+        self.env = self.find_variable(function.id, CLOSURE_ENV).map(|var| Self::var_location(&var));
+        if let Some(location) = self.env.clone() {
+            self.push_asm(format!("  movq %r10, {}", location));
         }
-        let add_return = true;
-        for stmt in &function.code.statements {
-            match stmt {
-                Statement::Block(blk) => {
-                    todo!("{:?}", blk)
-                }
-                Statement::FunctionCall(call) => {
-                    self.push_asm("# Function call");
-                    self.asm_pass_parameters(call.parameters.clone(), function.id)?;
-                    self.push_asm(format!("  call {}", call.name));
+        self.asm_spill_parameters(function);
+        let add_return = !matches!(function.code.statements.last(), Some(Statement::Return(_)));
+        self.asm_block(&function.code)?;
+        self.push_asm("# End Function");
+        if add_return {
+            self.push_asm("  xor  %eax, %eax");
+            self.push_asm("  movq %rbp, %rsp");
+            self.push_asm("  popq %rbp");
+            self.push_asm("  ret");
+        }
+        // Keep %rsp 16 byte aligned for calls
+        let frame = self.frame.next_multiple_of(16);
+        if frame > 0 {
+            self.asm[reserve] = format!("  subq ${}, %rsp", frame);
+        } else {
+            self.asm.remove(reserve);
+        }
+        Ok(())
+    }
+
+    /// Reserves a slot past the variables of the frame for a value being built, like a tuple or the struct
+    /// returned by a call. Each one is used by a single expression, so they are never released.
+    /// Returns the offset of the slot below %rbp
+    fn temporary(&mut self, var_type: &VarType) -> usize {
+        self.frame = (self.frame + var_type.size().next_multiple_of(8)).next_multiple_of(var_type.align());
+        self.frame
+    }
+
+    /// Copies the incoming arguments to the stack slots of the parameters,
+    /// the ones not passed in registers were pushed by the caller above the return address
+    fn asm_spill_parameters(&mut self, function: &Function) {
+        let mut types: Vec<VarType> = function.parameters.iter().map(|p| p.var_type.clone()).collect();
+        if self.return_buffer.is_some() {
+            types.insert(0, VarType::Pointer(Box::new(VarType::Void)));
+        }
+        let mut classes = Self::classify_arguments(&types, false);
+        if let Some(location) = self.return_buffer.clone() {
+            classes.remove(0);
+            self.push_asm(format!("  movq %rdi, {}", location));
+        }
+        let mut memory = 16;
+        // Copying a struct clobbers argument registers, so it waits until they are all spilled
+        let mut copies = Vec::new();
+        for (param, class) in function.parameters.iter().zip(classes) {
+            let var = self.resolve_variable(param);
+            let location = Self::var_location(&var);
+            match (&class, &var.var_type) {
+                (Some(registers), VarType::Struct(_)) => {
+                    let offset = var.stack.unwrap();
+                    for (word, register) in registers.iter().enumerate() {
+                        let source = match register {
+                            ArgRegister::Integer(idx) => REGS[*idx].x64.to_string(),
+                            ArgRegister::Sse(idx) => format!("xmm{}", idx),
+                        };
+                        self.push_asm(format!("  movq %{}, -{}(%rbp)", source, offset - word * 8));
+                    }
                 }
-                Statement::ExternFunctionCall(call) => {
-                    self.push_asm("# Extern function call");
-                    self.asm_pass_parameters(call.parameters.clone(), function.id)?;
-                    if call.name == "printf" {
-                        /*
-                        For libc printf and it's variants %AL contains the number of
-                        vector registers (XMM0-XMM7) used for floating-point arguments.
-                        First 8 float args goes in XMM0-XMM7.
-                        Push additional float args to stack in reverse order.
-                        For now we do not have floating point support, so we set it to 0.
-                         */
-                        let f = self.syntax.externs.get(&call.name).unwrap();
-                        if f.parameters.contains(&VarType::VarArgs) {
-                            self.push_asm("  xor %eax, %eax");
-                        }
+                (Some(registers), _) => match registers[0] {
+                    ArgRegister::Integer(idx) => {
+                        let (mov, reg) = Self::sized_mov(&var.var_type, &REGS[idx]);
+                        self.push_asm(format!("  {} %{}, {}", mov, reg, location));
                     }
-                    self.push_asm(format!("  call {}@PLT", call.name));
+                    ArgRegister::Sse(idx) => self.push_asm(format!("  movq %xmm{}, {}", idx, location)),
+                },
+                (None, VarType::Struct(_)) => copies.push((memory, location, var.var_type.size())),
+                (None, _) => {
+                    let (mov, reg) = Self::sized_mov(&var.var_type, &RAX);
+                    self.push_asm(format!("  movq {}(%rbp), %rax", memory));
+                    self.push_asm(format!("  {} %{}, {}", mov, reg, location));
                 }
+            }
+            if class.is_none() {
+                memory += Self::eightbytes(&var.var_type) * 8;
+            }
+        }
+        for (memory, location, size) in copies {
+            self.push_asm(format!("  leaq {}(%rbp), %rsi", memory));
+            self.push_asm(format!("  leaq {}, %rdi", location));
+            self.asm_copy(size);
+        }
+    }
+
+    /// Registers of the eightbytes of each argument, `None` for the ones passed in memory.
+    /// Eightbytes holding only floats go in xmm registers, the others in general purpose ones.
+    /// Structs up to 16 bytes need registers for all their eightbytes, bigger ones always go in memory.
+    /// The runtime takes every value as raw bits, so `raw` passes floats in general purpose registers.
+    fn classify_arguments(types: &[VarType], raw: bool) -> Vec<Option<Vec<ArgRegister>>> {
+        let (mut next_integer, mut next_sse) = (0, 0);
+        types
+            .iter()
+            .map(|var_type| {
+                let mut sse = vec![!raw; Self::eightbytes(var_type)];
+                Self::mark_integer_eightbytes(var_type, 0, &mut sse);
+                let sse_words = sse.iter().filter(|&&sse| sse).count();
+                let integer_words = sse.len() - sse_words;
+                if sse.len() > 2 || next_integer + integer_words > REGS.len() || next_sse + sse_words > SSE_REGS {
+                    return None;
+                }
+                let registers = sse
+                    .into_iter()
+                    .map(|sse| {
+                        let (next, register): (&mut usize, fn(usize) -> ArgRegister) = if sse {
+                            (&mut next_sse, ArgRegister::Sse)
+                        } else {
+                            (&mut next_integer, ArgRegister::Integer)
+                        };
+                        *next += 1;
+                        register(*next - 1)
+                    })
+                    .collect();
+                Some(registers)
+            })
+            .collect()
+    }
+
+    /// Clears the eightbytes holding anything but floats, `offset` is the position of the value in the argument
+    fn mark_integer_eightbytes(var_type: &VarType, offset: usize, sse: &mut [bool]) {
+        match var_type {
+            VarType::Struct(definition) => {
+                for field in &definition.fields {
+                    Self::mark_integer_eightbytes(&field.var_type, offset + field.offset, sse);
+                }
+            }
+            VarType::Array(element, length) => {
+                for idx in 0..*length {
+                    Self::mark_integer_eightbytes(element, offset + idx * element.size(), sse);
+                }
+            }
+            VarType::Float => {}
+            _ => {
+                if let Some(word) = sse.get_mut(offset / 8) {
+                    *word = false;
+                }
+            }
+        }
+    }
+
+    /// Registers of the eightbytes of a returned struct, classified like an argument: the integer ones go in
+    /// %rax and %rdx, the float ones in %xmm0 and %xmm1. `None` for structs returned in memory, which the caller
+    /// passes as a hidden first argument
+    fn classify_return(var_type: &VarType) -> Option<Vec<ArgRegister>> {
+        Self::classify_arguments(std::slice::from_ref(var_type), false).remove(0)
+    }
+
+    /// Stack words taken by an argument
+    fn eightbytes(var_type: &VarType) -> usize {
+        var_type.size().div_ceil(8)
+    }
+
+    fn asm_block(&mut self, block: &Block) -> Result<(), Box<dyn std::error::Error>> {
+        for stmt in &block.statements {
+            match stmt {
+                Statement::Block(blk) => self.asm_block(blk)?,
+                Statement::FunctionCall(call) => self.asm_call(call, false)?,
+                Statement::ExternFunctionCall(call) => self.asm_call(call, true)?,
                 Statement::Return(ret) => {
                     self.push_asm("# Return");
-                    //add_return = false;
+                    if let Some(value) = ret {
+                        // The value is already in %eax or %rax depending on its type
+                        self.asm_expression(value)?;
+                        match value.var_type() {
+                            VarType::Float => self.push_asm("  movq %rax, %xmm0"),
+                            VarType::Struct(_) => self.asm_return_struct(&value.var_type()),
+                            _ => {}
+                        }
+                    }
                     self.push_asm("  movq %rbp, %rsp");
                     self.push_asm("  popq %rbp");
-                    todo!("{:?}", ret)
+                    self.push_asm("  ret");
                 }
-                Statement::Assignment(name, rule, param) => {
-                    self.push_asm("# Assignment");
-                    let vt = self.syntax.variables.children.get(&function.id).unwrap();
-                    let var = self.find_variable(vt, name);
-                    if var.is_none() {
-                        return Err(format!("Cannot find variable {}", name).into());
+                Statement::Declaration(var) if matches!(var.var_type, VarType::Vec(_) | VarType::Map(_, _)) => {
+                    // A new empty container, so it can be filled by the functions it is passed to
+                    self.push_asm("# Declaration");
+                    let var = self.resolve_variable(var);
+                    if let VarType::Map(key, value) = &var.var_type {
+                        self.push_asm(format!("  movq ${}, %rdi", key.key_size()));
+                        self.push_asm(format!("  movq ${}, %rsi", value.size()));
+                        self.asm_aligned_call("map_new");
+                    } else {
+                        self.asm_aligned_call("vec_new");
                     }
-                    match rule {
-                        Rule::ASSIGN => match param.var_type {
-                            VarType::Int => todo!("{:?}", param.var_type),
-                            VarType::Char => todo!("{:?}", param.var_type),
-                            VarType::String => {
-                                self.push_asm(format!("  leaq .STR{}(%rip), %rax", param.id.unwrap()));
-                                self.push_asm(format!("  movq %rax, -{}(%rbp)", var.unwrap().stack.unwrap().to_string()));
-                            }
-                            VarType::Bool => todo!("{:?}", param.var_type),
-                            VarType::Void => todo!("{:?}", param.var_type),
-                            VarType::VarArgs => todo!("{:?}", param.var_type),
-                        },
-                        Rule::ASSIGN_PLUS => todo!("{:?}", rule),
-                        Rule::ASSIGN_MINUS => todo!("{:?}", rule),
-                        Rule::ASSIGN_MULTI => todo!("{:?}", rule),
-                        Rule::ASSIGN_DIV => todo!("{:?}", rule),
-                        Rule::ASSIGN_MOD => todo!("{:?}", rule),
-                        Rule::ASSIGN_AND => todo!("{:?}", rule),
-                        Rule::ASSIGN_OR => todo!("{:?}", rule),
-                        _ => panic!("Unknown assignment {:?}", rule),
+                    self.push_asm(format!("  movq %rax, {}", Self::var_location(&var)));
+                }
+                Statement::Declaration(var) => {
+                    self.push_asm("# Declaration");
+                    let var = self.resolve_variable(var);
+                    self.asm_zero(&var);
+                }
+                Statement::Assignment(target, _, value) if matches!(target.var_type(), VarType::Struct(_)) => {
+                    self.push_asm("# Struct assignment");
+                    self.asm_address(target)?;
+                    self.asm_push("rax");
+                    self.asm_expression(value)?;
+                    self.push_asm("  movq %rax, %rsi");
+                    self.asm_pop("rdi");
+                    self.asm_copy(target.var_type().size());
+                }
+                Statement::Assignment(target, rule, value) => {
+                    self.push_asm("# Assignment");
+                    let location = match target {
+                        Expression::Variable(var) => {
+                            self.asm_expression(value)?;
+                            Self::var_location(&self.resolve_variable(var))
+                        }
+                        _ => {
+                            // %rsi is left alone by the operators, including the division
+                            self.asm_address(target)?;
+                            self.asm_push("rax");
+                            self.asm_expression(value)?;
+                            self.asm_pop("rsi");
+                            "(%rsi)".to_string()
+                        }
+                    };
+                    let var_type = target.var_type();
+                    if *rule != Rule::ASSIGN {
+                        self.push_asm("  movq %rax, %rcx");
+                        self.asm_load_from(&var_type, &location, &RAX);
+                        let op = Self::assignment_operator(*rule);
+                        match &var_type {
+                            VarType::Pointer(element) => self.asm_pointer_operator(op, element, &value.var_type()),
+                            _ => self.asm_operator(op, &var_type),
+                        }
                     }
+                    let (mov, reg) = Self::sized_mov(&var_type, &RAX);
+                    self.push_asm(format!("  {} %{}, {}", mov, reg, location));
+                }
+                Statement::ForLoop(for_loop) => self.asm_for_loop(for_loop)?,
+                Statement::While(while_loop) => self.asm_while(while_loop)?,
+                Statement::Loop(inf_loop) => self.asm_loop(inf_loop)?,
+                Statement::Break(label) => {
+                    self.push_asm("# Break");
+                    let (_, _, target) = self.find_loop(label);
+                    self.push_asm(format!("  jmp {}", target));
                 }
-                _ => todo!("{:?}", stmt),
+                Statement::Continue(label) => {
+                    self.push_asm("# Continue");
+                    let (_, target, _) = self.find_loop(label);
+                    self.push_asm(format!("  jmp {}", target));
+                }
+                Statement::Case(case) => self.asm_case(case)?,
+                Statement::If(stmt) => self.asm_if(stmt)?,
             }
         }
-        self.push_asm("# End Function");
-        if add_return {
-            self.push_asm("  xor  %eax, %eax");
-            self.push_asm("  movq %rbp, %rsp");
-            self.push_asm("  popq %rbp");
-            self.push_asm("  ret");
-        }
         Ok(())
     }
 
-    fn asm_pass_parameters(&mut self, params: Vec<Parameter>, func_id: usize) -> Result<(), Box<dyn std::error::Error>> {
-        for idx in 0..params.len() {
-            let param = &params[idx];
-            if idx > 5 {
-                self.asm_push_parameter(param, func_id)?;
-            } else {
-                self.asm_pass_param(param, REGS[idx].x64, func_id)?;
+    /// Returns the struct whose address is in %rax, in registers or copied to the memory of the caller
+    fn asm_return_struct(&mut self, var_type: &VarType) {
+        self.push_asm("  movq %rax, %rsi");
+        let Some(registers) = Self::classify_return(var_type) else {
+            let location = self.return_buffer.clone().unwrap();
+            self.push_asm(format!("  movq {}, %rdi", location));
+            self.asm_copy(var_type.size());
+            // The address of the copy is returned too
+            self.push_asm(format!("  movq {}, %rax", location));
+            return;
+        };
+        // Copied to whole eightbytes first, so the loads do not read past the end of the struct
+        self.push_asm(format!("  subq ${}, %rsp", registers.len() * 8));
+        self.pushed += registers.len();
+        self.push_asm("  movq %rsp, %rdi");
+        self.asm_copy(var_type.size());
+        for register in registers {
+            match register {
+                ArgRegister::Integer(idx) => self.asm_pop(RETURN_REGS[idx]),
+                ArgRegister::Sse(idx) => {
+                    self.asm_pop("rcx");
+                    self.push_asm(format!("  movq %rcx, %xmm{}", idx));
+                }
             }
         }
+    }
+
+    fn asm_for_loop(&mut self, for_loop: &ForLoop) -> Result<(), Box<dyn std::error::Error>> {
+        if let VarType::Vec(element) | VarType::Map(element, _) = for_loop.iterable.var_type() {
+            return self.asm_for_vec(for_loop, &element);
+        }
+        let label = self.gen_label();
+        let cursor = self.find_variable(for_loop.code.id, FOR_CURSOR).unwrap();
+        let variable = self.resolve_variable(&for_loop.variable);
+        self.push_asm("# For loop");
+        self.asm_expression(&for_loop.iterable)?;
+        self.push_asm(format!("  movq %rax, {}", Self::var_location(&cursor)));
+        self.push_asm(format!(".LFOR{}:", label));
+        self.push_asm(format!("  movq {}, %rax", Self::var_location(&cursor)));
+        self.push_asm("  movzbl (%rax), %eax");
+        self.push_asm("  testb %al, %al");
+        self.push_asm(format!("  je .LFOREND{}", label));
+        self.push_asm(format!("  movb %al, {}", Self::var_location(&variable)));
+        self.loops
+            .push((for_loop.label.clone(), format!(".LFORNEXT{}", label), format!(".LFOREND{}", label)));
+        self.asm_block(&for_loop.code)?;
+        self.loops.pop();
+        self.push_asm(format!(".LFORNEXT{}:", label));
+        self.push_asm(format!("  incq {}", Self::var_location(&cursor)));
+        self.push_asm(format!("  jmp .LFOR{}", label));
+        self.push_asm(format!(".LFOREND{}:", label));
         Ok(())
     }
 
-    fn find_variable(&self, vt: &VarTree, name: &str) -> Option<Variable> {
-        for var_info in &vt.variables {
-            if var_info.name == name {
-                return Some(var_info.clone());
+    /// Visits the elements of a vec, or the keys of a map, by index.
+    /// The length is read on every step so the body can add to the container
+    fn asm_for_vec(&mut self, for_loop: &ForLoop, element: &VarType) -> Result<(), Box<dyn std::error::Error>> {
+        let label = self.gen_label();
+        let cursor = self.find_variable(for_loop.code.id, FOR_CURSOR).unwrap();
+        let index = self.find_variable(for_loop.code.id, FOR_INDEX).unwrap();
+        let variable = self.resolve_variable(&for_loop.variable);
+        self.push_asm("# For loop");
+        self.asm_expression(&for_loop.iterable)?;
+        self.push_asm(format!("  movq %rax, {}", Self::var_location(&cursor)));
+        self.push_asm(format!("  movq $0, {}", Self::var_location(&index)));
+        self.push_asm(format!(".LFOR{}:", label));
+        self.push_asm(format!("  movq {}, %rax", Self::var_location(&cursor)));
+        // A vec that was never pushed to is still null
+        self.push_asm("  testq %rax, %rax");
+        self.push_asm(format!("  je .LFOREND{}", label));
+        self.push_asm(format!("  movq {}, %rcx", Self::var_location(&index)));
+        self.push_asm("  cmpq 8(%rax), %rcx");
+        self.push_asm(format!("  jge .LFOREND{}", label));
+        self.push_asm("  movq (%rax), %rax");
+        self.asm_scaled_add(element.size());
+        self.asm_load_from(element, "(%rax)", &RAX);
+        let (mov, reg) = Self::sized_mov(element, &RAX);
+        self.push_asm(format!("  {} %{}, {}", mov, reg, Self::var_location(&variable)));
+        self.loops
+            .push((for_loop.label.clone(), format!(".LFORNEXT{}", label), format!(".LFOREND{}", label)));
+        self.asm_block(&for_loop.code)?;
+        self.loops.pop();
+        self.push_asm(format!(".LFORNEXT{}:", label));
+        self.push_asm(format!("  incq {}", Self::var_location(&index)));
+        self.push_asm(format!("  jmp .LFOR{}", label));
+        self.push_asm(format!(".LFOREND{}:", label));
+        Ok(())
+    }
+
+    fn asm_while(&mut self, while_loop: &While) -> Result<(), Box<dyn std::error::Error>> {
+        let label = self.gen_label();
+        self.push_asm("# While loop");
+        self.push_asm(format!(".LWHILE{}:", label));
+        self.asm_jump(&while_loop.condition, &format!(".LWHILEEND{}", label), false)?;
+        self.loops.push((
+            while_loop.label.clone(),
+            format!(".LWHILE{}", label),
+            format!(".LWHILEEND{}", label),
+        ));
+        self.asm_block(&while_loop.code)?;
+        self.loops.pop();
+        self.push_asm(format!("  jmp .LWHILE{}", label));
+        self.push_asm(format!(".LWHILEEND{}:", label));
+        Ok(())
+    }
+
+    fn asm_loop(&mut self, inf_loop: &Loop) -> Result<(), Box<dyn std::error::Error>> {
+        let label = self.gen_label();
+        self.push_asm("# Loop");
+        self.push_asm(format!(".LLOOP{}:", label));
+        self.loops
+            .push((inf_loop.label.clone(), format!(".LLOOP{}", label), format!(".LLOOPEND{}", label)));
+        self.asm_block(&inf_loop.code)?;
+        self.loops.pop();
+        self.push_asm(format!("  jmp .LLOOP{}", label));
+        self.push_asm(format!(".LLOOPEND{}:", label));
+        Ok(())
+    }
+
+    /// Innermost loop, or the one with the given label. Syntax already checked that it exists.
+    fn find_loop(&self, label: &Option<String>) -> (Option<String>, String, String) {
+        let found = match label {
+            Some(_) => self.loops.iter().rev().find(|(l, _, _)| l == label),
+            None => self.loops.last(),
+        };
+        found.unwrap().clone()
+    }
+
+    fn asm_case(&mut self, case: &Case) -> Result<(), Box<dyn std::error::Error>> {
+        let label = self.gen_label();
+        self.push_asm("# Case");
+        self.asm_expression(&case.value)?;
+
+        let mut values: Vec<(i64, usize)> = case.arms.iter().enumerate().map(|(idx, (value, _))| (*value, idx)).collect();
+        values.sort();
+        let min = values.first().map(|(v, _)| *v).unwrap_or(0);
+        let max = values.last().map(|(v, _)| *v).unwrap_or(0);
+        let wide = case.value.var_type().size() == 8;
+        let contiguous = !wide && values.len() > 1 && max - min + 1 == values.len() as i64;
+
+        if contiguous {
+            // Dense values: index a table of offsets relative to the table itself
+            self.push_asm(format!("  subl ${}, %eax", min));
+            self.push_asm(format!("  cmpl ${}, %eax", max - min));
+            self.push_asm(format!("  ja .LCASEEND{}", label));
+            self.push_asm(format!("  leaq .LCASETABLE{}(%rip), %rdx", label));
+            self.push_asm("  movslq (%rdx,%rax,4), %rax");
+            self.push_asm("  addq %rdx, %rax");
+            self.push_asm("  jmp *%rax");
+            self.rodata.push(".align 4".to_string());
+            self.rodata.push(format!(".LCASETABLE{}:", label));
+            for (_, idx) in &values {
+                self.rodata.push(format!("  .long .LCASE{}_{}-.LCASETABLE{}", label, idx, label));
+            }
+        } else {
+            for (value, idx) in &values {
+                if wide {
+                    // cmpq only takes 32 bit immediates
+                    self.push_asm(format!("  movabsq ${}, %rcx", value));
+                    self.push_asm("  cmpq %rcx, %rax");
+                } else {
+                    self.push_asm(format!("  cmpl ${}, %eax", value));
+                }
+                self.push_asm(format!("  je .LCASE{}_{}", label, idx));
             }
+            self.push_asm(format!("  jmp .LCASEEND{}", label));
         }
-        // Todo - check upper the three
-        None
+
+        for (idx, (_, body)) in case.arms.iter().enumerate() {
+            self.push_asm(format!(".LCASE{}_{}:", label, idx));
+            self.asm_block(body)?;
+            self.push_asm(format!("  jmp .LCASEEND{}", label));
+        }
+        self.push_asm(format!(".LCASEEND{}:", label));
+        Ok(())
     }
 
-    fn asm_pass_param(&mut self, param: &Parameter, dest: &str, func_id: usize) -> Result<(), Box<dyn std::error::Error>> {
-        match param.var_type {
-            VarType::Int => {
-                if param.is_literal {
-                    self.push_asm(format!("  movq ${}, %{}", param.clone().value.unwrap(), dest));
+    fn asm_if(&mut self, stmt: &If) -> Result<(), Box<dyn std::error::Error>> {
+        let label = self.gen_label();
+        self.push_asm("# If");
+        self.asm_jump(&stmt.condition, &format!(".LELSE{}", label), false)?;
+        self.asm_block(&stmt.then)?;
+        if let Some(otherwise) = &stmt.otherwise {
+            self.push_asm(format!("  jmp .LENDIF{}", label));
+            self.push_asm(format!(".LELSE{}:", label));
+            self.asm_block(otherwise)?;
+        } else {
+            self.push_asm(format!(".LELSE{}:", label));
+        }
+        self.push_asm(format!(".LENDIF{}:", label));
+        Ok(())
+    }
+
+    /// Jumps to `target` when the boolean expression evaluates to `when`, falls through otherwise
+    fn asm_jump(&mut self, expr: &Expression, target: &str, when: bool) -> Result<(), Box<dyn std::error::Error>> {
+        match expr {
+            Expression::Binary(lhs, op @ (Rule::AND | Rule::OR), rhs) => {
+                // `a && b` jumps on true only when both are true, `a || b` jumps on false only when both are false
+                if (*op == Rule::AND) != when {
+                    self.asm_jump(lhs, target, when)?;
+                    self.asm_jump(rhs, target, when)?;
                 } else {
-                    todo!("{:?}", param);
+                    let skip = format!(".LSKIP{}", self.gen_label());
+                    self.asm_jump(lhs, &skip, !when)?;
+                    self.asm_jump(rhs, target, when)?;
+                    self.push_asm(format!("{}:", skip));
                 }
             }
-            VarType::Char => {
-                todo!()
+            Expression::Unary(Rule::NOT, operand) => self.asm_jump(operand, target, !when)?,
+            Expression::Binary(lhs, op @ (Rule::EQ | Rule::NEQ), rhs) if lhs.var_type() == VarType::Float => {
+                self.asm_compare(lhs, *op, rhs)?;
+                // NaN sets the parity flag and is equal to nothing, itself included
+                if (*op == Rule::EQ) == when {
+                    let skip = format!(".LSKIP{}", self.gen_label());
+                    self.push_asm(format!("  jp {}", skip));
+                    self.push_asm(format!("  je {}", target));
+                    self.push_asm(format!("{}:", skip));
+                } else {
+                    self.push_asm(format!("  jp {}", target));
+                    self.push_asm(format!("  jne {}", target));
+                }
+            }
+            Expression::Binary(lhs, op, rhs) if Self::is_comparison(*op) => {
+                self.asm_compare(lhs, *op, rhs)?;
+                let mut cc = Self::condition_code(*op, &lhs.var_type());
+                if !when {
+                    cc = Self::negate_condition_code(cc);
+                }
+                self.push_asm(format!("  j{} {}", cc, target));
+            }
+            _ => {
+                self.asm_expression(expr)?;
+                self.push_asm("  testl %eax, %eax");
+                self.push_asm(format!("  {} {}", if when { "jne" } else { "je" }, target));
+            }
+        }
+        Ok(())
+    }
+
+    /// Evaluates an expression, the result is left in %rax
+    fn asm_expression(&mut self, expr: &Expression) -> Result<(), Box<dyn std::error::Error>> {
+        match expr {
+            Expression::Value(param) => self.asm_load(param, &RAX),
+            Expression::Variable(var) => {
+                let var = self.resolve_variable(var);
+                self.asm_load_variable(&var, &RAX);
             }
-            VarType::String => {
-                if param.is_literal {
-                    self.push_asm(format!("  leaq .STR{}(%rip), %{}", param.id.unwrap(), dest));
+            Expression::Convert(value, var_type) => {
+                self.asm_expression(value)?;
+                self.asm_convert(&value.var_type(), var_type);
+            }
+            Expression::Index(_, _, _) | Expression::Field(_, _) | Expression::Capture(_, _) => {
+                self.asm_address(expr)?;
+                self.asm_load_from(&expr.var_type(), "(%rax)", &RAX);
+            }
+            Expression::FunctionCall(call) => self.asm_call(call, false)?,
+            Expression::ExternFunctionCall(call) => self.asm_call(call, true)?,
+            Expression::FunctionAddress(name, _) => {
+                let record = self.record(name);
+                self.push_asm(format!("  leaq {}(%rip), %rax", record));
+            }
+            Expression::Closure(closure) => self.asm_closure(closure)?,
+            Expression::Tuple(elements, var_type) => {
+                // Built in a temporary, like any other struct the value is its address
+                let VarType::Struct(definition) = var_type else {
+                    panic!("Tuple of type {:?}", var_type);
+                };
+                let offset = self.temporary(var_type);
+                for (element, field) in elements.iter().zip(&definition.fields) {
+                    self.asm_expression(element)?;
+                    let location = format!("-{}(%rbp)", offset - field.offset);
+                    if let VarType::Struct(_) = field.var_type {
+                        self.push_asm("  movq %rax, %rsi");
+                        self.push_asm(format!("  leaq {}, %rdi", location));
+                        self.asm_copy(field.var_type.size());
+                    } else {
+                        let (mov, reg) = Self::sized_mov(&field.var_type, &RAX);
+                        self.push_asm(format!("  {} %{}, {}", mov, reg, location));
+                    }
+                }
+                self.push_asm(format!("  leaq -{}(%rbp), %rax", offset));
+            }
+            Expression::Binary(_, Rule::AND | Rule::OR, _) => {
+                let label = self.gen_label();
+                self.asm_jump(expr, &format!(".LTRUE{}", label), true)?;
+                self.push_asm("  xor %eax, %eax");
+                self.push_asm(format!("  jmp .LBOOL{}", label));
+                self.push_asm(format!(".LTRUE{}:", label));
+                self.push_asm("  movl $1, %eax");
+                self.push_asm(format!(".LBOOL{}:", label));
+            }
+            Expression::Binary(lhs, op @ (Rule::EQ | Rule::NEQ), rhs) if lhs.var_type() == VarType::Float => {
+                self.asm_compare(lhs, *op, rhs)?;
+                // NaN sets the parity flag and is equal to nothing, itself included
+                if *op == Rule::EQ {
+                    self.push_asm("  sete %al");
+                    self.push_asm("  setnp %cl");
+                    self.push_asm("  andb %cl, %al");
                 } else {
-                    let vt = self.syntax.variables.children.get(&func_id).unwrap();
-                    let var = self.find_variable(vt, param.name.as_str());
-                    if var.is_none() {
-                        return Err(format!("Cannot find variable {}", param.name).into());
+                    self.push_asm("  setne %al");
+                    self.push_asm("  setp %cl");
+                    self.push_asm("  orb %cl, %al");
+                }
+                self.push_asm("  movzbl %al, %eax");
+            }
+            Expression::Binary(lhs, op, rhs) if Self::is_comparison(*op) => {
+                self.asm_compare(lhs, *op, rhs)?;
+                self.push_asm(format!("  set{} %al", Self::condition_code(*op, &lhs.var_type())));
+                self.push_asm("  movzbl %al, %eax");
+            }
+            Expression::Unary(Rule::ADDRESS, operand) => self.asm_address(operand)?,
+            Expression::Unary(Rule::DEREF, _) => {
+                self.asm_address(expr)?;
+                self.asm_load_from(&expr.var_type(), "(%rax)", &RAX);
+            }
+            Expression::Unary(op, operand) => {
+                self.asm_expression(operand)?;
+                match op {
+                    // Flips the sign bit
+                    Rule::MINUS if expr.var_type() == VarType::Float => self.push_asm("  btcq $63, %rax"),
+                    Rule::MINUS if expr.var_type().size() == 8 => self.push_asm("  negq %rax"),
+                    Rule::MINUS => {
+                        self.push_asm("  negl %eax");
+                        self.asm_normalize(&expr.var_type());
                     }
-                    self.push_asm(format!("  movq -{}(%rbp), %{}", var.unwrap().stack.unwrap().to_string(), dest));
+                    Rule::NOT => self.push_asm("  xorl $1, %eax"),
+                    _ => panic!("Unknown operator {:?}", op),
                 }
             }
-            VarType::Bool => {
-                todo!()
+            Expression::Binary(lhs, op, rhs) if matches!(lhs.var_type(), VarType::Pointer(_)) => {
+                let VarType::Pointer(element) = lhs.var_type() else {
+                    unreachable!()
+                };
+                self.asm_binary_operands(lhs, rhs)?;
+                self.asm_pointer_operator(*op, &element, &rhs.var_type());
+            }
+            Expression::Binary(lhs, op, rhs) => {
+                self.asm_binary_operands(lhs, rhs)?;
+                self.asm_operator(*op, &lhs.var_type());
             }
-            VarType::Void => return Err("Cannot pass void type".into()),
-            VarType::VarArgs => return Err("Cannot pass varargs".into()),
         }
         Ok(())
     }
 
-    fn asm_push_parameter(&mut self, param: &Parameter, func_id: usize) -> Result<(), Box<dyn std::error::Error>> {
-        match param.var_type {
-            VarType::Int => {
-                todo!()
+    /// Computes the address of a variable or an array element into %rax
+    fn asm_address(&mut self, expr: &Expression) -> Result<(), Box<dyn std::error::Error>> {
+        match expr {
+            Expression::Variable(var) => {
+                let var = self.resolve_variable(var);
+                self.push_asm(format!("  leaq {}, %rax", Self::var_location(&var)));
             }
-            VarType::Char => {
-                todo!()
+            Expression::Index(sequence, index, line) if matches!(sequence.var_type(), VarType::String | VarType::Vec(_)) => {
+                let (length, element) = match sequence.var_type() {
+                    VarType::Vec(element) => ("vec_len", *element),
+                    _ => ("string_len", VarType::Char),
+                };
+                self.asm_expression(index)?;
+                self.asm_widen(&index.var_type(), &RAX);
+                self.asm_push("rax");
+                self.asm_expression(sequence)?;
+                if self.bounds_check {
+                    // The length is only known at runtime, the index is kept on the stack meanwhile
+                    self.asm_push("rax");
+                    self.push_asm("  movq %rax, %rdi");
+                    self.asm_aligned_call(length);
+                    self.push_asm("  movslq %eax, %rdx");
+                    self.push_asm("  movq 8(%rsp), %rax");
+                    self.asm_bounds_check("%rdx", *line);
+                    self.asm_pop("rax");
+                }
+                if let VarType::Vec(_) = sequence.var_type() {
+                    // The header of a vec starts with the pointer to its elements
+                    self.push_asm("  movq (%rax), %rax");
+                }
+                self.asm_pop("rcx");
+                self.asm_scaled_add(element.size());
+            }
+            Expression::Index(array, index, line) => {
+                let VarType::Array(element, length) = array.var_type() else {
+                    panic!("Cannot index {:?}", array.var_type());
+                };
+                self.asm_expression(index)?;
+                self.asm_widen(&index.var_type(), &RAX);
+                if self.bounds_check {
+                    self.asm_bounds_check(&format!("${}", length), *line);
+                }
+                self.asm_push("rax");
+                self.asm_address(array)?;
+                self.asm_pop("rcx");
+                self.asm_scaled_add(element.size());
             }
-            VarType::String => {
-                self.push_asm(format!("  leaq .STR{}(%rip), %rax", param.id.unwrap()));
-                self.push_asm("  pushq %rax");
+            Expression::Unary(Rule::DEREF, pointer) => self.asm_expression(pointer)?,
+            Expression::Capture(offset, _) => {
+                let env = self.env.clone().unwrap();
+                self.push_asm(format!("  movq {}, %rax", env));
+                self.push_asm(format!("  addq ${}, %rax", offset));
             }
-            VarType::Bool => {
-                todo!()
+            Expression::Field(value, name) => {
+                let VarType::Struct(definition) = value.var_type() else {
+                    panic!("Cannot access field {} of {:?}", name, value.var_type());
+                };
+                self.asm_address(value)?;
+                let offset = definition.field(name).unwrap().offset;
+                if offset > 0 {
+                    self.push_asm(format!("  addq ${}, %rax", offset));
+                }
             }
-            VarType::Void => {
-                todo!()
+            // Struct values, like tuples and returned structs, are already addresses
+            _ if matches!(expr.var_type(), VarType::Struct(_)) => self.asm_expression(expr)?,
+            _ => return Err(format!("Cannot take the address of {:?}", expr).into()),
+        }
+        Ok(())
+    }
+
+    /// Builds the environment record of a closure through the runtime, %rax gets its address.
+    /// Captured values are copied into it, references keep the address of the variable
+    fn asm_closure(&mut self, closure: &Closure) -> Result<(), Box<dyn std::error::Error>> {
+        self.push_asm(format!("  leaq {}(%rip), %rdi", closure.name));
+        self.push_asm(format!("  movq ${}, %rsi", closure.size));
+        self.asm_aligned_call("closure_new");
+        self.asm_push("rax");
+        for capture in &closure.captures {
+            let location = format!("{}(%rcx)", capture.offset);
+            let var_type = capture.source.var_type();
+            if capture.by_reference {
+                self.asm_address(&capture.source)?;
+                self.push_asm("  movq (%rsp), %rcx");
+                self.push_asm(format!("  movq %rax, {}", location));
+                continue;
             }
-            VarType::VarArgs => {
-                todo!()
+            self.asm_expression(&capture.source)?;
+            self.push_asm("  movq (%rsp), %rcx");
+            if let VarType::Struct(_) | VarType::Array(_, _) = var_type {
+                self.push_asm("  movq %rax, %rsi");
+                self.push_asm(format!("  leaq {}, %rdi", location));
+                self.asm_copy(var_type.size());
+            } else {
+                let (mov, reg) = Self::sized_mov(&var_type, &RAX);
+                self.push_asm(format!("  {} %{}, {}", mov, reg, location));
             }
         }
+        self.asm_pop("rax");
         Ok(())
     }
 
+    /// Adds %rcx elements of `size` bytes to the address in %rax
+    fn asm_scaled_add(&mut self, size: usize) {
+        match size {
+            1 | 2 | 4 | 8 => self.push_asm(format!("  leaq (%rax,%rcx,{}), %rax", size)),
+            _ => {
+                self.push_asm(format!("  imulq ${}, %rcx", size));
+                self.push_asm("  addq %rcx, %rax");
+            }
+        }
+    }
+
+    /// Pointer arithmetic between the pointer in %rax and the offset or pointer in %rcx, counted in elements
+    fn asm_pointer_operator(&mut self, op: Rule, element: &VarType, rhs: &VarType) {
+        if let VarType::Pointer(_) = rhs {
+            self.push_asm("  subq %rcx, %rax");
+            if element.size() > 1 {
+                self.push_asm("  cqto");
+                self.push_asm(format!("  movq ${}, %rcx", element.size()));
+                self.push_asm("  idivq %rcx");
+            }
+            return;
+        }
+        self.asm_widen(rhs, &RCX);
+        if op == Rule::MINUS {
+            self.push_asm("  negq %rcx");
+        }
+        self.asm_scaled_add(element.size());
+    }
+
+    /// Aborts through the runtime when the index in %rax is not below `length`, an immediate or a register.
+    /// Negative indexes are caught by the unsigned comparison
+    fn asm_bounds_check(&mut self, length: &str, line: usize) {
+        let label = self.gen_label();
+        let source = self.syntax.source_line(line).replace('\\', "\\\\").replace('"', "\\\"");
+        self.rodata.push(format!(".BOUNDS{}:", label));
+        self.rodata.push(format!("  .string \"line {}: {}\"", line, source));
+        self.push_asm(format!("  cmpq {}, %rax", length));
+        self.push_asm(format!("  jb .LINBOUNDS{}", label));
+        self.push_asm(format!("  movq {}, %rdx", length));
+        self.push_asm(format!("  leaq .BOUNDS{}(%rip), %rdi", label));
+        self.push_asm("  movq %rax, %rsi");
+        // Never returns, so the stack can be realigned without restoring it
+        self.push_asm("  andq $-16, %rsp");
+        self.push_asm("  call out_of_bounds");
+        self.push_asm(format!(".LINBOUNDS{}:", label));
+    }
+
+    /// Fills a variable with zeros
+    fn asm_zero(&mut self, var: &Variable) {
+        let location = Self::var_location(var);
+        match var.var_type {
+            VarType::Array(_, _) | VarType::Struct(_) => {
+                self.push_asm(format!("  leaq {}, %rdi", location));
+                self.push_asm(format!("  movq ${}, %rcx", var.var_type.size()));
+                self.push_asm("  xor %eax, %eax");
+                self.push_asm("  rep stosb");
+            }
+            _ => {
+                let (mov, _) = Self::sized_mov(&var.var_type, &RAX);
+                self.push_asm(format!("  {} $0, {}", mov, location));
+            }
+        }
+    }
+
+    /// Copies `size` bytes from (%rsi) to (%rdi)
+    fn asm_copy(&mut self, size: usize) {
+        self.push_asm(format!("  movq ${}, %rcx", size));
+        self.push_asm("  rep movsb");
+    }
+
+    /// Applies an arithmetic operator to %rax and %rcx, the result is left in %rax.
+    /// Types up to 32 bits are computed on the 32 bit registers and normalized afterwards.
+    fn asm_operator(&mut self, op: Rule, var_type: &VarType) {
+        if *var_type == VarType::Float {
+            self.asm_float_operator(op);
+            return;
+        }
+        let (suffix, rax, rcx, rdx) = match var_type.size() {
+            8 => ("q", RAX.x64, RCX.x64, "rdx"),
+            _ => ("l", RAX.x32, RCX.x32, "edx"),
+        };
+        match op {
+            Rule::PLUS => self.push_asm(format!("  add{} %{}, %{}", suffix, rcx, rax)),
+            Rule::MINUS => self.push_asm(format!("  sub{} %{}, %{}", suffix, rcx, rax)),
+            Rule::MULTI => self.push_asm(format!("  imul{} %{}, %{}", suffix, rcx, rax)),
+            Rule::DIV | Rule::MOD => {
+                if var_type.is_signed() {
+                    self.push_asm(if suffix == "q" { "  cqto" } else { "  cltd" });
+                    self.push_asm(format!("  idiv{} %{}", suffix, rcx));
+                } else {
+                    self.push_asm("  xorl %edx, %edx");
+                    self.push_asm(format!("  div{} %{}", suffix, rcx));
+                }
+                if op == Rule::MOD {
+                    self.push_asm(format!("  mov{} %{}, %{}", suffix, rdx, rax));
+                }
+            }
+            Rule::AND => self.push_asm(format!("  and{} %{}, %{}", suffix, rcx, rax)),
+            Rule::OR => self.push_asm(format!("  or{} %{}, %{}", suffix, rcx, rax)),
+            _ => panic!("Unknown operator {:?}", op),
+        }
+        self.asm_normalize(var_type);
+    }
+
+    /// Float arithmetic between %rax and %rcx, the operands go through %xmm0 and %xmm1
+    fn asm_float_operator(&mut self, op: Rule) {
+        let instruction = match op {
+            Rule::PLUS => "addsd",
+            Rule::MINUS => "subsd",
+            Rule::MULTI => "mulsd",
+            Rule::DIV => "divsd",
+            _ => panic!("Unknown float operator {:?}", op),
+        };
+        self.push_asm("  movq %rax, %xmm0");
+        self.push_asm("  movq %rcx, %xmm1");
+        self.push_asm(format!("  {} %xmm1, %xmm0", instruction));
+        self.push_asm("  movq %xmm0, %rax");
+    }
+
+    /// Converts the number in %rax between integer, char and float types
+    fn asm_convert(&mut self, from: &VarType, to: &VarType) {
+        match (from, to) {
+            (VarType::Float, VarType::Float) => {}
+            (VarType::U64, VarType::Float) => {
+                // Above 2^63 the value is halved, keeping the low bit so it still rounds right, and doubled back
+                let label = self.gen_label();
+                self.push_asm("  testq %rax, %rax");
+                self.push_asm(format!("  js .LU2F{}", label));
+                self.push_asm("  cvtsi2sdq %rax, %xmm0");
+                self.push_asm(format!("  jmp .LU2FEND{}", label));
+                self.push_asm(format!(".LU2F{}:", label));
+                self.push_asm("  movq %rax, %rcx");
+                self.push_asm("  shrq %rax");
+                self.push_asm("  andl $1, %ecx");
+                self.push_asm("  orq %rcx, %rax");
+                self.push_asm("  cvtsi2sdq %rax, %xmm0");
+                self.push_asm("  addsd %xmm0, %xmm0");
+                self.push_asm(format!(".LU2FEND{}:", label));
+                self.push_asm("  movq %xmm0, %rax");
+            }
+            (VarType::Float, VarType::U64) => {
+                // From 2^63 on, 2^63 is subtracted before converting and put back as the top bit
+                let label = self.gen_label();
+                self.push_asm("  movq %rax, %xmm0");
+                self.push_asm(format!("  movabsq ${}, %rcx", ((1u64 << 63) as f64).to_bits()));
+                self.push_asm("  movq %rcx, %xmm1");
+                self.push_asm("  ucomisd %xmm1, %xmm0");
+                self.push_asm(format!("  jae .LF2U{}", label));
+                self.push_asm("  cvttsd2siq %xmm0, %rax");
+                self.push_asm(format!("  jmp .LF2UEND{}", label));
+                self.push_asm(format!(".LF2U{}:", label));
+                self.push_asm("  subsd %xmm1, %xmm0");
+                self.push_asm("  cvttsd2siq %xmm0, %rax");
+                self.push_asm("  btcq $63, %rax");
+                self.push_asm(format!(".LF2UEND{}:", label));
+            }
+            (_, VarType::Float) => {
+                self.asm_widen(from, &RAX);
+                self.push_asm("  cvtsi2sdq %rax, %xmm0");
+                self.push_asm("  movq %xmm0, %rax");
+            }
+            (VarType::Float, _) => {
+                self.push_asm("  movq %rax, %xmm0");
+                self.push_asm("  cvttsd2siq %xmm0, %rax");
+                self.asm_normalize(to);
+            }
+            // Narrower types are already extended to 32 bits
+            _ if to.size() == 8 => self.asm_widen(from, &RAX),
+            _ => self.asm_normalize(to),
+        }
+    }
+
+    /// Extends a value narrower than 32 bits from the low bits of %eax, after it was computed in a wider
+    /// register or returned by a function, which leaves the upper bits undefined
+    fn asm_normalize(&mut self, var_type: &VarType) {
+        match var_type {
+            VarType::I8 => self.push_asm("  movsbl %al, %eax"),
+            VarType::U8 | VarType::Char | VarType::Bool => self.push_asm("  movzbl %al, %eax"),
+            VarType::I16 => self.push_asm("  movswl %ax, %eax"),
+            VarType::U16 => self.push_asm("  movzwl %ax, %eax"),
+            _ => {}
+        }
+    }
+
+    /// Extends an integer to 64 bits in place
+    fn asm_widen(&mut self, var_type: &VarType, reg: &Register) {
+        if var_type.size() == 8 {
+            return;
+        }
+        if var_type.is_signed() {
+            self.push_asm(format!("  movslq %{}, %{}", reg.x32, reg.x64));
+        } else if var_type.size() == 4 {
+            // Narrower unsigned values are already zero extended, a 32 bit move clears the upper half
+            self.push_asm(format!("  movl %{}, %{}", reg.x32, reg.x32));
+        }
+    }
+
+    /// Operator applied by a compound assignment
+    fn assignment_operator(rule: Rule) -> Rule {
+        match rule {
+            Rule::ASSIGN_PLUS => Rule::PLUS,
+            Rule::ASSIGN_MINUS => Rule::MINUS,
+            Rule::ASSIGN_MULTI => Rule::MULTI,
+            Rule::ASSIGN_DIV => Rule::DIV,
+            Rule::ASSIGN_MOD => Rule::MOD,
+            Rule::ASSIGN_AND => Rule::AND,
+            Rule::ASSIGN_OR => Rule::OR,
+            _ => panic!("Unknown assignment {:?}", rule),
+        }
+    }
+
+    /// Evaluates both operands, leaving the left one in %rax and the right one in %rcx
+    fn asm_binary_operands(&mut self, lhs: &Expression, rhs: &Expression) -> Result<(), Box<dyn std::error::Error>> {
+        self.asm_expression(rhs)?;
+        self.asm_push("rax");
+        self.asm_expression(lhs)?;
+        self.asm_pop("rcx");
+        Ok(())
+    }
+
+    fn asm_compare(&mut self, lhs: &Expression, op: Rule, rhs: &Expression) -> Result<(), Box<dyn std::error::Error>> {
+        self.asm_binary_operands(lhs, rhs)?;
+        if lhs.var_type() == VarType::Float {
+            // Sets the flags like an unsigned comparison, and all of ZF, PF and CF when a side is NaN.
+            // `<` and `<=` compare the other way around, so every ordering test is false for NaN
+            self.push_asm("  movq %rax, %xmm0");
+            self.push_asm("  movq %rcx, %xmm1");
+            match op {
+                Rule::LT | Rule::LTE => self.push_asm("  ucomisd %xmm0, %xmm1"),
+                _ => self.push_asm("  ucomisd %xmm1, %xmm0"),
+            }
+        } else if lhs.var_type().size() == 8 {
+            self.push_asm("  cmpq %rcx, %rax");
+        } else {
+            self.push_asm("  cmpl %ecx, %eax");
+        }
+        Ok(())
+    }
+
+    fn is_comparison(op: Rule) -> bool {
+        matches!(op, Rule::EQ | Rule::NEQ | Rule::GT | Rule::LT | Rule::GTE | Rule::LTE)
+    }
+
+    /// Condition code suffix for `set` and `j`, chars, pointers and floats compare unsigned.
+    /// Float operands of `<` and `<=` are swapped by `asm_compare`
+    fn condition_code(op: Rule, var_type: &VarType) -> &'static str {
+        let signed = var_type.is_signed();
+        match op {
+            Rule::EQ => "e",
+            Rule::NEQ => "ne",
+            Rule::LT if *var_type == VarType::Float => "a",
+            Rule::LTE if *var_type == VarType::Float => "ae",
+            Rule::GT if signed => "g",
+            Rule::GT => "a",
+            Rule::LT if signed => "l",
+            Rule::LT => "b",
+            Rule::GTE if signed => "ge",
+            Rule::GTE => "ae",
+            Rule::LTE if signed => "le",
+            Rule::LTE => "be",
+            _ => panic!("Unknown comparison {:?}", op),
+        }
+    }
+
+    fn negate_condition_code(cc: &str) -> &'static str {
+        match cc {
+            "e" => "ne",
+            "ne" => "e",
+            "g" => "le",
+            "le" => "g",
+            "l" => "ge",
+            "ge" => "l",
+            "a" => "be",
+            "be" => "a",
+            "b" => "ae",
+            "ae" => "b",
+            _ => panic!("Unknown condition code {}", cc),
+        }
+    }
+
+    /// Calls a function, the return value is left in %rax.
+    /// A returned struct is stored in a temporary, %rax gets its address
+    fn asm_call(&mut self, call: &FnCall, external: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let (buffer, registers) = match &call.return_type {
+            VarType::Struct(_) => (Some(self.temporary(&call.return_type)), Self::classify_return(&call.return_type)),
+            _ => (None, None),
+        };
+        // Structs that do not fit in registers are written by the callee to the memory passed as first argument
+        let hidden = buffer.filter(|_| registers.is_none()).map(|offset| format!("-{}(%rbp)", offset));
+        if !external {
+            self.push_asm("# Function call");
+            let (release, _) = self.asm_pass_parameters(&call.parameters, false, hidden)?;
+            match &call.callee {
+                Some(callee) => {
+                    // Only variables and captures are called, loading them leaves the argument registers alone.
+                    // The record goes in %r10, where closures find their captures
+                    self.asm_expression(callee)?;
+                    self.push_asm("  movq %rax, %r10");
+                    self.push_asm("  call *(%rax)");
+                }
+                None => self.push_asm(format!("  call {}", call.name)),
+            }
+            self.asm_release_parameters(release);
+            if call.return_type == VarType::Float {
+                self.push_asm("  movq %xmm0, %rax");
+            }
+        } else {
+            self.push_asm("# Extern function call");
+            // Functions of the runtime are not declared
+            let variadic = self
+                .syntax
+                .externs
+                .get(&call.name)
+                .map(|f| f.parameters.contains(&VarType::VarArgs));
+            let (release, sse) = self.asm_pass_parameters(&call.parameters, variadic.is_none(), hidden)?;
+            if variadic == Some(true) {
+                // For variadic functions, like printf and its variants, %al holds the number of xmm registers used
+                self.push_asm(format!("  movl ${}, %eax", sse));
+            }
+            self.push_asm(format!("  call {}@PLT", call.name));
+            self.asm_release_parameters(release);
+            if call.return_type == VarType::Float && variadic.is_some() {
+                self.push_asm("  movq %xmm0, %rax");
+            }
+        }
+        if let Some(offset) = buffer {
+            for (word, register) in registers.into_iter().flatten().enumerate() {
+                let source = match register {
+                    ArgRegister::Integer(idx) => RETURN_REGS[idx].to_string(),
+                    ArgRegister::Sse(idx) => format!("xmm{}", idx),
+                };
+                self.push_asm(format!("  movq %{}, -{}(%rbp)", source, offset - word * 8));
+            }
+            self.push_asm(format!("  leaq -{}(%rbp), %rax", offset));
+        }
+        self.asm_normalize(&call.return_type);
+        Ok(())
+    }
+
+    /// Evaluates the arguments right to left on the stack, the ones passed in memory first so they stay
+    /// right above the return address, then pops the others into registers.
+    /// `hidden` is the memory for a returned struct, passed before the arguments.
+    /// Returns the number of bytes left on the stack, to be released after the call, and of xmm registers used.
+    fn asm_pass_parameters(
+        &mut self,
+        params: &[Expression],
+        raw: bool,
+        hidden: Option<String>,
+    ) -> Result<(usize, usize), Box<dyn std::error::Error>> {
+        let mut types: Vec<VarType> = params.iter().map(|p| p.var_type()).collect();
+        if hidden.is_some() {
+            types.insert(0, VarType::Pointer(Box::new(VarType::Void)));
+        }
+        let classes = Self::classify_arguments(&types, raw);
+        let words = |in_registers: bool| -> usize {
+            types
+                .iter()
+                .zip(&classes)
+                .filter(|(_, class)| class.is_some() == in_registers)
+                .map(|(var_type, _)| Self::eightbytes(var_type))
+                .sum()
+        };
+        let stack_words = words(false);
+        // %rsp must be 16 byte aligned at the call
+        let padding = (self.pushed + stack_words) % 2;
+        if padding == 1 {
+            self.push_asm("  subq $8, %rsp");
+            self.pushed += 1;
+        }
+        let skip = hidden.is_some() as usize;
+        for in_registers in [false, true] {
+            for (param, class) in params.iter().zip(&classes[skip..]).rev() {
+                if class.is_some() == in_registers {
+                    self.asm_push_argument(param)?;
+                }
+            }
+        }
+        if let Some(location) = hidden {
+            self.push_asm(format!("  leaq {}, %rax", location));
+            self.asm_push("rax");
+        }
+        let mut sse = 0;
+        for register in classes.iter().flatten().flatten() {
+            match register {
+                ArgRegister::Integer(idx) => self.asm_pop(REGS[*idx].x64),
+                ArgRegister::Sse(idx) => {
+                    self.asm_pop("rax");
+                    self.push_asm(format!("  movq %rax, %xmm{}", idx));
+                    sse += 1;
+                }
+            }
+        }
+        Ok(((stack_words + padding) * 8, sse))
+    }
+
+    /// Pushes an argument, structs are copied by value as whole eightbytes
+    fn asm_push_argument(&mut self, param: &Expression) -> Result<(), Box<dyn std::error::Error>> {
+        self.asm_expression(param)?;
+        let var_type = param.var_type();
+        if let VarType::Struct(_) = var_type {
+            let words = Self::eightbytes(&var_type);
+            self.push_asm(format!("  subq ${}, %rsp", words * 8));
+            self.pushed += words;
+            self.push_asm("  movq %rax, %rsi");
+            self.push_asm("  movq %rsp, %rdi");
+            self.asm_copy(var_type.size());
+        } else {
+            self.asm_push("rax");
+        }
+        Ok(())
+    }
+
+    fn asm_release_parameters(&mut self, bytes: usize) {
+        if bytes > 0 {
+            self.push_asm(format!("  addq ${}, %rsp", bytes));
+            self.pushed -= bytes / 8;
+        }
+    }
+
+    /// Calls a runtime function with the arguments already in registers, realigning %rsp if needed
+    fn asm_aligned_call(&mut self, name: &str) {
+        let padding = self.pushed % 2 == 1;
+        if padding {
+            self.push_asm("  subq $8, %rsp");
+        }
+        self.push_asm(format!("  call {}@PLT", name));
+        if padding {
+            self.push_asm("  addq $8, %rsp");
+        }
+    }
+
+    fn asm_push(&mut self, reg: &str) {
+        self.push_asm(format!("  pushq %{}", reg));
+        self.pushed += 1;
+    }
+
+    fn asm_pop(&mut self, reg: &str) {
+        self.push_asm(format!("  popq %{}", reg));
+        self.pushed -= 1;
+    }
+
+    fn find_variable(&self, scope: usize, name: &str) -> Option<Variable> {
+        let root = &self.syntax.variables;
+        let mut tree = root.find_tree(scope);
+        while let Some(vt) = tree {
+            for var_info in &vt.variables {
+                if var_info.name == name {
+                    return Some(var_info.clone());
+                }
+            }
+            tree = vt.father.and_then(|father| root.find_tree(father));
+        }
+        None
+    }
+
+    /// Same variable with its stack slot assigned
+    fn resolve_variable(&self, var: &Variable) -> Variable {
+        self.find_variable(var.scope, &var.name).unwrap()
+    }
+
+    /// Locals live in the stack frame, globals (which have no stack slot) are addressed relative to %rip
+    fn var_location(var: &Variable) -> String {
+        match var.stack {
+            Some(offset) => format!("-{}(%rbp)", offset),
+            None => format!("{}(%rip)", Self::global_symbol(&var.name)),
+        }
+    }
+
+    /// Move instruction and register name matching the size of the type
+    fn sized_mov<'r>(var_type: &VarType, reg: &Register<'r>) -> (&'static str, &'r str) {
+        match var_type.size() {
+            1 => ("movb", reg.x8),
+            2 => ("movw", reg.x16),
+            4 => ("movl", reg.x32),
+            _ => ("movq", reg.x64),
+        }
+    }
+
+    /// Loads a variable into a register, arrays and structs load their address
+    fn asm_load_variable(&mut self, var: &Variable, dest: &Register) {
+        let location = Self::var_location(var);
+        self.asm_load_from(&var.var_type, &location, dest);
+    }
+
+    /// Loads a value of the given type from memory, values smaller than 32 bits are sign or zero extended
+    fn asm_load_from(&mut self, var_type: &VarType, location: &str, dest: &Register) {
+        match var_type {
+            VarType::I8 => self.push_asm(format!("  movsbl {}, %{}", location, dest.x32)),
+            VarType::U8 | VarType::Char | VarType::Bool => self.push_asm(format!("  movzbl {}, %{}", location, dest.x32)),
+            VarType::I16 => self.push_asm(format!("  movswl {}, %{}", location, dest.x32)),
+            VarType::U16 => self.push_asm(format!("  movzwl {}, %{}", location, dest.x32)),
+            VarType::Int | VarType::U32 => self.push_asm(format!("  movl {}, %{}", location, dest.x32)),
+            VarType::Array(_, _) | VarType::Struct(_) => self.push_asm(format!("  leaq {}, %{}", location, dest.x64)),
+            _ => self.push_asm(format!("  movq {}, %{}", location, dest.x64)),
+        }
+    }
+
+    fn asm_load(&mut self, param: &Parameter, dest: &Register) {
+        match param.id {
+            Some(id) => self.push_asm(format!("  leaq .STR{}(%rip), %{}", id, dest.x64)),
+            None if param.var_type.size() == 8 => self.push_asm(format!("  movabsq ${}, %{}", param.value, dest.x64)),
+            None => self.push_asm(format!("  movl ${}, %{}", param.value, dest.x32)),
+        }
+    }
+
+    fn gen_label(&mut self) -> usize {
+        let label = self.next_label;
+        self.next_label += 1;
+        label
+    }
+
     fn push_asm<T: std::borrow::Borrow<str>>(&mut self, s: T) {
         self.asm.push(s.borrow().into());
     }
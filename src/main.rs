@@ -17,11 +17,16 @@ const FILE_INPUT: Option<&str> = Some("assets/2015_01.aoc");
 const FILE_INPUT: Option<&str> = None;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    // --debug checks array indexes at runtime
+    let debug = args.iter().any(|arg| arg == "--debug");
     let file_path = if let Some(file_path) = FILE_INPUT {
         file_path.to_string()
     } else {
-        let args: Vec<String> = env::args().collect();
-        let file_path = args.get(1).ok_or("Usage: program <file_path>")?;
+        let file_path = args
+            .iter()
+            .find(|arg| !arg.starts_with("--"))
+            .ok_or("Usage: program [--debug] <file_path>")?;
         file_path.to_string()
     };
 
@@ -35,7 +40,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     syntax.analyze()?;
     syntax.optimize()?;
 
-    let mut assembler = Assembler::new(syntax);
+    let mut assembler = Assembler::new(syntax, debug);
     let code = assembler.assemble()?;
 
     println!("{}", code);
@@ -53,6 +58,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let output = Command::new("gcc")
         .arg(asm_file)
         .arg("./assets/utils.c")
+        .arg("./assets/runtime.c")
+        // Math functions like sqrt can be declared as externs
+        .arg("-lm")
         .arg("-o")
         .arg("./a.out")
         .output()
@@ -2,15 +2,25 @@
 use crate::lexer::Rule;
 use pest::iterators::{Pair, Pairs};
 use std::collections::HashMap;
+use std::fmt;
+use std::iter::Peekable;
+use std::rc::Rc;
 
 #[derive(Debug)]
 pub struct Syntax<'a> {
     content: Pair<'a, Rule>,
     pub(crate) externs: HashMap<String, ExternFunction>,
     pub(crate) functions: HashMap<String, Function>,
+    structs: HashMap<String, Rc<Struct>>,
     pub(crate) variables: VarTree,
     pub(crate) strings: Vec<String>,
+    pub(crate) initializers: HashMap<String, Expression>,
     next_vartree: usize,
+    return_type: VarType,
+    /// Labels of the loops enclosing the statement being parsed
+    loops: Vec<Option<String>>,
+    /// Functions enclosing the lambdas being parsed, innermost last
+    closures: Vec<ClosureScope>,
 }
 
 impl<'a> Syntax<'a> {
@@ -19,6 +29,7 @@ impl<'a> Syntax<'a> {
         let functions = HashMap::new();
         let strings = Vec::new();
         let variables = VarTree {
+            id: 0,
             father: None,
             variables: Vec::new(),
             children: HashMap::new(),
@@ -28,9 +39,14 @@ impl<'a> Syntax<'a> {
             content,
             externs,
             functions,
+            structs: HashMap::new(),
             variables,
             strings,
-            next_vartree: 0,
+            initializers: HashMap::new(),
+            next_vartree: 1,
+            return_type: VarType::Void,
+            loops: Vec::new(),
+            closures: Vec::new(),
         }
     }
 
@@ -53,9 +69,8 @@ impl<'a> Syntax<'a> {
         match rule {
             Rule::extern_function => self.parse_extern_function(pair),
             Rule::function => self.parse_function(pair),
-            Rule::declaration => {
-                todo!()
-            }
+            Rule::struct_definition => self.parse_struct(pair),
+            Rule::declaration => self.parse_global(pair),
             Rule::EOI => Ok(()),
             _ => {
                 todo!("{:?}", pair)
@@ -63,31 +78,182 @@ impl<'a> Syntax<'a> {
         }
     }
 
+    fn parse_global(&mut self, pair: Pair<Rule>) -> Result<(), Box<dyn std::error::Error>> {
+        // Globals are declared like locals, directly in the root scope
+        let empty = VarTree {
+            id: 0,
+            father: None,
+            variables: Vec::new(),
+            children: HashMap::new(),
+            stack: 0,
+        };
+        let mut vars = std::mem::replace(&mut self.variables, empty);
+        let mut code = Block {
+            id: 0,
+            statements: Vec::new(),
+        };
+        let result = self.declaration(pair, &mut code, &mut vars);
+        self.variables = vars;
+        result?;
+
+        for stmt in code.statements {
+            if let Statement::Assignment(Expression::Variable(var), _, value) = stmt {
+                let name = var.name;
+                let is_literal = matches!(&value, Expression::Value(param) if matches!(param.var_type, VarType::String | VarType::Float | VarType::U64))
+                    || matches!(&value, Expression::FunctionAddress(_, _));
+                if !is_literal && value.constant().is_none() {
+                    return Err(format!("Global initializer must be constant: {}", name).into());
+                }
+                self.initializers.insert(name, value);
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_struct(&mut self, pair: Pair<Rule>) -> Result<(), Box<dyn std::error::Error>> {
+        let mut inner = pair.into_inner();
+        let name = inner.next().unwrap().as_span().as_str().to_string();
+        if self.structs.contains_key(&name) {
+            return Err(format!("Struct already declared: {}", name).into());
+        }
+        let mut fields: Vec<(String, VarType)> = Vec::new();
+        for field in inner {
+            let mut field = field.into_inner();
+            let field_type = self.parse_type(&field.next().unwrap())?;
+            let field_name = field.next().unwrap().as_span().as_str().to_string();
+            if field_type == VarType::Void || field_type == VarType::VarArgs {
+                return Err(format!("Cannot declare {:?} field: {}", field_type, field_name).into());
+            }
+            let var_type = Self::array_type(field_type, &mut field, &field_name)?;
+            if fields.iter().any(|(f, _)| *f == field_name) {
+                return Err(format!("Duplicate field {} in struct {}", field_name, name).into());
+            }
+            fields.push((field_name, var_type));
+        }
+        if fields.is_empty() {
+            return Err(format!("Struct has no fields: {}", name).into());
+        }
+        let definition = Struct::new(name.clone(), fields);
+        self.structs.insert(name, Rc::new(definition));
+        Ok(())
+    }
+
+    /// Type named by a builtin type or a struct
+    fn parse_type(&self, pair: &Pair<Rule>) -> Result<VarType, Box<dyn std::error::Error>> {
+        let mut inner = pair.clone().into_inner();
+        let base = inner.next().unwrap();
+        let mut var_type = match base.as_rule() {
+            Rule::vec_type => {
+                let element = self.parse_type(&base.into_inner().next().unwrap())?;
+                Self::check_element(&element)?;
+                VarType::Vec(Box::new(element))
+            }
+            Rule::map_type => {
+                let mut types = base.into_inner();
+                let key = self.parse_type(&types.next().unwrap())?;
+                let value = self.parse_type(&types.next().unwrap())?;
+                if !key.is_integer() && !matches!(key, VarType::Char | VarType::String) {
+                    return Err(format!("Cannot use {:?} as a map key", key).into());
+                }
+                Self::check_element(&value)?;
+                VarType::Map(Box::new(key), Box::new(value))
+            }
+            Rule::fn_type => {
+                let mut parameters = Vec::new();
+                let mut return_type = VarType::Void;
+                for part in base.into_inner() {
+                    if part.as_rule() == Rule::return_type {
+                        return_type = self.parse_type(&part.into_inner().next().unwrap())?;
+                        continue;
+                    }
+                    let parameter = self.parse_type(&part)?;
+                    // Called like aoc functions, which can not be variadic
+                    if matches!(parameter, VarType::Void | VarType::VarArgs) {
+                        return Err(format!("Cannot use {:?} as a parameter of a function type", parameter).into());
+                    }
+                    parameters.push(parameter);
+                }
+                if return_type == VarType::VarArgs {
+                    return Err("Cannot return ... from a function type".into());
+                }
+                VarType::Function(parameters, Box::new(return_type))
+            }
+            Rule::tuple_type => {
+                let mut elements = Vec::new();
+                for element in base.into_inner() {
+                    let element = self.parse_type(&element)?;
+                    Self::check_tuple_element(&element)?;
+                    elements.push(element);
+                }
+                VarType::tuple(elements)
+            }
+            Rule::identifier => {
+                let name = base.as_span().as_str();
+                match self.structs.get(name) {
+                    Some(definition) => VarType::Struct(definition.clone()),
+                    None => return Err(format!("Unknown type: {}", name).into()),
+                }
+            }
+            rule => VarType::from_rule(&rule),
+        };
+        // Every `*` adds a level of indirection
+        for _ in inner {
+            if var_type == VarType::VarArgs {
+                return Err("Cannot point to ...".into());
+            }
+            var_type = VarType::Pointer(Box::new(var_type));
+        }
+        Ok(var_type)
+    }
+
+    /// Vec elements and map values are moved by the runtime as single values of at most 8 bytes
+    fn check_element(element: &VarType) -> Result<(), Box<dyn std::error::Error>> {
+        if matches!(
+            element,
+            VarType::Void | VarType::VarArgs | VarType::Array(_, _) | VarType::Struct(_)
+        ) {
+            return Err(format!("Cannot store {:?} in a vec or map", element).into());
+        }
+        Ok(())
+    }
+
+    /// Tuple elements are copied in and out like struct fields, arrays can not be copied as a whole
+    fn check_tuple_element(element: &VarType) -> Result<(), Box<dyn std::error::Error>> {
+        if matches!(element, VarType::Void | VarType::VarArgs | VarType::Null | VarType::Array(_, _)) {
+            return Err(format!("Cannot store {:?} in a tuple", element).into());
+        }
+        Ok(())
+    }
+
+    /// Type of the values returned by a function, structs and tuples are returned like C does
+    fn parse_return_type(&self, pair: Pair<Rule>, name: &str) -> Result<VarType, Box<dyn std::error::Error>> {
+        let return_type = self.parse_type(&pair.into_inner().next().unwrap())?;
+        if return_type == VarType::VarArgs {
+            return Err(format!("Function {} cannot return {:?}", name, return_type).into());
+        }
+        Ok(return_type)
+    }
+
     fn parse_function(&mut self, pair: Pair<Rule>) -> Result<(), Box<dyn std::error::Error>> {
         let mut inner = pair.into_inner();
         let name = inner.next().unwrap().as_span().as_str().to_string();
-        let mut parameters = HashMap::new();
+        let id = self.gen_id();
+        let mut parameters: Vec<Variable> = Vec::new();
         if let Some(params) = Syntax::expect(&inner, Rule::parameter_list) {
             inner.next();
-            for param in params.into_inner() {
-                let mut param = param.into_inner();
-                let var_type = param.next().unwrap();
-                let name = param.next().unwrap();
-                let param_type = VarType::from_str(var_type.as_span().as_str());
-                let name = name.as_span().as_str().to_string();
-                parameters.insert(name, param_type);
-            }
+            parameters = self.parse_parameters(params, id, &name)?;
         }
         let mut return_type = VarType::Void;
         if let Some(rt) = Syntax::expect(&inner, Rule::return_type) {
             inner.next();
-            let rt = rt.into_inner().next().unwrap();
-            return_type = VarType::from_str(rt.as_span().as_str());
+            return_type = self.parse_return_type(rt, &name)?;
         }
-        let id = self.gen_id();
+        self.return_type = return_type.clone();
+        // Parameters are the first variables of the function scope
         let mut vars = VarTree {
+            id,
             father: Some(0),
-            variables: Vec::new(),
+            variables: parameters.clone(),
             children: HashMap::new(),
             stack: 0,
         };
@@ -102,11 +268,12 @@ impl<'a> Syntax<'a> {
             },
         };
 
+        // Known before parsing the body, so the function can call itself
+        self.functions.insert(name.clone(), function.clone());
+
         if let Some(block) = Syntax::expect(&inner, Rule::block) {
             inner.next();
-            for pair in block.into_inner() {
-                self.parse_statement(pair, &mut function.code, &mut vars)?;
-            }
+            self.parse_function_body(block, &mut function.code, &mut vars)?;
         } else {
             return Err(format!("No code block for function: {}", name).into());
         }
@@ -116,6 +283,43 @@ impl<'a> Syntax<'a> {
         Ok(())
     }
 
+    /// Statements of a function or a lambda. An empty body still returns, so `optimize` keeps it: its address
+    /// can be taken and calls to it must link
+    fn parse_function_body(&mut self, block: Pair<Rule>, code: &mut Block, vars: &mut VarTree) -> Result<(), Box<dyn std::error::Error>> {
+        for pair in block.into_inner() {
+            self.parse_statement(pair, code, vars)?;
+        }
+        if code.statements.is_empty() {
+            code.statements.push(Statement::Return(None));
+        }
+        Ok(())
+    }
+
+    /// Parameters of a function or a lambda, the first variables of its scope
+    fn parse_parameters(&self, pair: Pair<Rule>, scope: usize, name: &str) -> Result<Vec<Variable>, Box<dyn std::error::Error>> {
+        let mut parameters: Vec<Variable> = Vec::new();
+        for param in pair.into_inner() {
+            let mut param = param.into_inner();
+            let var_type = param.next().unwrap();
+            let param_name = param.next().unwrap();
+            let param_type = self.parse_type(&var_type)?;
+            let param_name = param_name.as_span().as_str().to_string();
+            if param_type == VarType::Void || param_type == VarType::VarArgs {
+                return Err(format!("Cannot declare {:?} parameter: {}", param_type, param_name).into());
+            }
+            if parameters.iter().any(|p| p.name == param_name) {
+                return Err(format!("Duplicate parameter {} in function {}", param_name, name).into());
+            }
+            parameters.push(Variable {
+                name: param_name,
+                var_type: param_type,
+                scope,
+                stack: None,
+            });
+        }
+        Ok(parameters)
+    }
+
     fn parse_statement(&mut self, pair: Pair<Rule>, code: &mut Block, vars: &mut VarTree) -> Result<(), Box<dyn std::error::Error>> {
         let rule = pair.as_rule();
         if rule != Rule::statement {
@@ -125,213 +329,1444 @@ impl<'a> Syntax<'a> {
         let rule = pair.as_rule();
         match rule {
             Rule::function_call => self.function_call(pair, code, vars),
-            Rule::return_statement => {
-                todo!()
+            Rule::return_statement => self.return_statement(pair, code, vars),
+            Rule::declaration => self.declaration(pair, code, vars),
+            Rule::destructuring => self.destructuring(pair, code, vars),
+            Rule::assignment => self.assignment(pair, code, vars),
+            Rule::for_loop => self.for_loop(pair, code, vars),
+            Rule::while_loop => self.while_loop(pair, code, vars),
+            Rule::infinite_loop => self.infinite_loop(pair, code, vars),
+            Rule::break_statement | Rule::continue_statement => self.loop_jump(pair, code),
+            Rule::case_statement => self.case_statement(pair, code, vars),
+            Rule::if_statement => self.if_statement(pair, code, vars),
+            Rule::expression_statement => self.expression_statement(pair, code, vars),
+            Rule::block => {
+                let block = self.parse_body(pair, code, vars)?;
+                code.statements.push(Statement::Block(block));
+                Ok(())
+            }
+            _ => {
+                todo!("{:?}", pair)
+            }
+        }
+    }
+
+    fn function_call(&mut self, pair: Pair<Rule>, code: &mut Block, vars: &mut VarTree) -> Result<(), Box<dyn std::error::Error>> {
+        let call = self.parse_call(pair, code, vars)?;
+        code.statements.push(call);
+        Ok(())
+    }
+
+    /// Method calls used as statements, any other value would be discarded
+    fn expression_statement(&mut self, pair: Pair<Rule>, code: &mut Block, vars: &mut VarTree) -> Result<(), Box<dyn std::error::Error>> {
+        let expression = pair.into_inner().next().unwrap();
+        let text = expression.as_span().as_str().trim().to_string();
+        let stmt = match self.parse_expression(expression, code, vars)? {
+            Expression::FunctionCall(call) => Statement::FunctionCall(call),
+            Expression::ExternFunctionCall(call) => Statement::ExternFunctionCall(call),
+            _ => return Err(format!("Unused expression: {}", text).into()),
+        };
+        code.statements.push(stmt);
+        Ok(())
+    }
+
+    fn parse_call(&mut self, pair: Pair<Rule>, code: &Block, vars: &VarTree) -> Result<Statement, Box<dyn std::error::Error>> {
+        let mut inner = pair.into_inner();
+        let name = inner.next().unwrap().as_span().as_str().to_string();
+        let mut arguments = Vec::new();
+        if let Some(args) = Syntax::expect(&inner, Rule::argument_list) {
+            inner.next();
+            let args = args.into_inner();
+            for arg in args {
+                let arg = arg.into_inner().next().unwrap();
+                let argument = self.parse_expression(arg, code, vars)?;
+                arguments.push(argument);
+            }
+        }
+        // A variable holding a function shadows the functions with the same name
+        if let Some(callee) = self.variable(vars, code.id, &name)?
+            && let VarType::Function(expected, return_type) = callee.var_type()
+        {
+            Self::check_arguments(&name, &expected, &mut arguments)?;
+            let fn_call = FnCall {
+                name,
+                parameters: arguments,
+                return_type: *return_type,
+                callee: Some(Box::new(callee)),
+            };
+            return Ok(Statement::FunctionCall(fn_call));
+        }
+        if let Some(function) = self.externs.get(&name) {
+            Self::check_arguments(&name, &function.parameters, &mut arguments)?;
+            // C calls functions directly, it takes and returns their code instead of their record
+            for (argument, expected) in arguments.iter_mut().zip(&function.parameters) {
+                if let VarType::Function(_, _) = expected {
+                    *argument = Self::runtime_call("closure_code", vec![argument.clone()], VarType::Pointer(Box::new(VarType::Void)));
+                }
+            }
+            let mut fn_call = FnCall {
+                name,
+                parameters: arguments,
+                return_type: function.return_type.clone(),
+                callee: None,
+            };
+            if let VarType::Function(_, _) = fn_call.return_type {
+                let return_type = std::mem::replace(&mut fn_call.return_type, VarType::Pointer(Box::new(VarType::Void)));
+                let code = Expression::ExternFunctionCall(fn_call);
+                let Expression::ExternFunctionCall(record) = Self::runtime_call("closure_from_code", vec![code], return_type) else {
+                    unreachable!()
+                };
+                return Ok(Statement::ExternFunctionCall(record));
+            }
+            return Ok(Statement::ExternFunctionCall(fn_call));
+        }
+        if let Some(function) = self.functions.get(&name) {
+            let expected: Vec<VarType> = function.parameters.iter().map(|p| p.var_type.clone()).collect();
+            Self::check_arguments(&name, &expected, &mut arguments)?;
+            let fn_call = FnCall {
+                name,
+                parameters: arguments,
+                return_type: function.return_type.clone(),
+                callee: None,
+            };
+            return Ok(Statement::FunctionCall(fn_call));
+        }
+        Err(format!("Unknown function: {}", name).into())
+    }
+
+    /// Checks the arguments of a call against the declared parameter types, converting integers where needed.
+    /// Anything is accepted in place of `...`
+    fn check_arguments(name: &str, expected: &[VarType], arguments: &mut [Expression]) -> Result<(), Box<dyn std::error::Error>> {
+        let variadic = expected.last() == Some(&VarType::VarArgs);
+        let fixed = if variadic { expected.len() - 1 } else { expected.len() };
+        if arguments.len() < fixed || (!variadic && arguments.len() > fixed) {
+            return Err(format!("Function {} expects {} arguments, got {}", name, fixed, arguments.len()).into());
+        }
+        for (idx, argument) in arguments.iter_mut().enumerate() {
+            if idx < fixed {
+                *argument = Self::coerce(argument.clone(), &expected[idx])?;
+            } else if argument.var_type() == VarType::BigInt {
+                // Extra arguments are formatted in decimal, so bigints print with `%s`
+                *argument = Self::runtime_call("bigint_to_string", vec![argument.clone()], VarType::String);
+            }
+            let arg_type = argument.var_type();
+            if arg_type == VarType::Void {
+                return Err(format!("Cannot pass Void to function {}", name).into());
+            }
+            if let VarType::Array(_, _) = arg_type {
+                return Err(format!("Cannot pass {:?} to function {}", arg_type, name).into());
+            }
+            if idx < fixed && !arg_type.converts_to(&expected[idx]) {
+                return Err(format!(
+                    "Argument {} of function {} must be {:?}, got {:?}",
+                    idx + 1,
+                    name,
+                    expected[idx],
+                    arg_type
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    fn for_loop(&mut self, pair: Pair<Rule>, code: &mut Block, vars: &mut VarTree) -> Result<(), Box<dyn std::error::Error>> {
+        let mut inner = pair.into_inner();
+        let label = self.loop_label(&mut inner)?;
+        let var_type = self.parse_type(&inner.next().unwrap())?;
+        let name = inner.next().unwrap().as_span().as_str().to_string();
+        let iterable = self.parse_expression(inner.next().unwrap(), code, vars)?;
+        let element = match iterable.var_type() {
+            VarType::String => VarType::Char,
+            VarType::Vec(element) => *element,
+            // The keys, in no particular order
+            VarType::Map(key, _) => *key,
+            other => return Err(format!("Cannot iterate over {:?}", other).into()),
+        };
+        if var_type != element {
+            return Err(format!(
+                "Cannot iterate over {:?} with {:?} variable: {}",
+                iterable.var_type(),
+                var_type,
+                name
+            )
+            .into());
+        }
+
+        // The loop variable and the hidden cursor live in the scope of the loop body
+        let mut body = self.new_block(code, vars);
+        let scope = vars.find_tree_mut(body.id).unwrap();
+        scope.variables.push(Variable {
+            name: FOR_CURSOR.to_string(),
+            var_type: iterable.var_type(),
+            scope: body.id,
+            stack: None,
+        });
+        if let VarType::Vec(_) | VarType::Map(_, _) = iterable.var_type() {
+            scope.variables.push(Variable {
+                name: FOR_INDEX.to_string(),
+                var_type: VarType::I64,
+                scope: body.id,
+                stack: None,
+            });
+        }
+        let variable = Variable {
+            name,
+            var_type,
+            scope: body.id,
+            stack: None,
+        };
+        scope.variables.push(variable.clone());
+
+        self.loops.push(label.clone());
+        for pair in inner.next().unwrap().into_inner() {
+            self.parse_statement(pair, &mut body, vars)?;
+        }
+        self.loops.pop();
+
+        code.statements.push(Statement::ForLoop(ForLoop {
+            label,
+            variable,
+            iterable,
+            code: body,
+        }));
+        Ok(())
+    }
+
+    fn while_loop(&mut self, pair: Pair<Rule>, code: &mut Block, vars: &mut VarTree) -> Result<(), Box<dyn std::error::Error>> {
+        let mut inner = pair.into_inner();
+        let label = self.loop_label(&mut inner)?;
+        let condition = self.parse_condition(inner.next().unwrap(), code, vars)?;
+        self.loops.push(label.clone());
+        let body = self.parse_body(inner.next().unwrap(), code, vars)?;
+        self.loops.pop();
+        code.statements.push(Statement::While(While {
+            label,
+            condition,
+            code: body,
+        }));
+        Ok(())
+    }
+
+    fn infinite_loop(&mut self, pair: Pair<Rule>, code: &mut Block, vars: &mut VarTree) -> Result<(), Box<dyn std::error::Error>> {
+        let mut inner = pair.into_inner();
+        let label = self.loop_label(&mut inner)?;
+        self.loops.push(label.clone());
+        let body = self.parse_body(inner.next().unwrap(), code, vars)?;
+        self.loops.pop();
+        code.statements.push(Statement::Loop(Loop { label, code: body }));
+        Ok(())
+    }
+
+    /// Consumes the optional label in front of a loop
+    fn loop_label(&self, inner: &mut Pairs<Rule>) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let Some(label) = Syntax::expect(inner, Rule::loop_label) else {
+            return Ok(None);
+        };
+        inner.next();
+        let name = label.into_inner().next().unwrap().as_span().as_str().to_string();
+        if self.loops.iter().flatten().any(|l| *l == name) {
+            return Err(format!("Loop label already in use: {}", name).into());
+        }
+        Ok(Some(name))
+    }
+
+    fn loop_jump(&mut self, pair: Pair<Rule>, code: &mut Block) -> Result<(), Box<dyn std::error::Error>> {
+        let rule = pair.as_rule();
+        let mut inner = pair.into_inner();
+        let keyword = inner.next().unwrap().as_span().as_str().to_string();
+        let label = inner.next().map(|l| l.as_span().as_str().to_string());
+        if self.loops.is_empty() {
+            return Err(format!("Cannot {} outside of a loop", keyword).into());
+        }
+        if let Some(label) = &label
+            && !self.loops.iter().flatten().any(|l| l == label)
+        {
+            return Err(format!("Unknown loop label: {}", label).into());
+        }
+        if rule == Rule::break_statement {
+            code.statements.push(Statement::Break(label));
+        } else {
+            code.statements.push(Statement::Continue(label));
+        }
+        Ok(())
+    }
+
+    fn case_statement(&mut self, pair: Pair<Rule>, code: &mut Block, vars: &mut VarTree) -> Result<(), Box<dyn std::error::Error>> {
+        let mut inner = pair.into_inner();
+        let name = inner.next().unwrap().as_span().as_str().to_string();
+        let Some(value) = self.variable(vars, code.id, &name)? else {
+            return Err(format!("Unknown variable: {}", name).into());
+        };
+        let var_type = value.var_type();
+        if !VAR_TYPES_CASE.contains(&var_type) {
+            return Err(format!("Cannot use {:?} in case statement: {}", var_type, name).into());
+        }
+
+        let mut arms: Vec<(i64, Block)> = Vec::new();
+        for item in inner {
+            let mut item = item.into_inner().peekable();
+            let negative = item.next_if(|pair| pair.as_rule() == Rule::MINUS).is_some();
+            let value = item.next().unwrap();
+            if value.as_rule() != Rule::literal {
+                return Err(format!("Case arm must be a literal, got: {}", value.as_span().as_str()).into());
+            }
+            let literal = value.into_inner().next().unwrap();
+            let literal_type = VarType::from_rule(&literal.as_rule());
+            // Integer literals match any integer type they fit in
+            let integer = literal.as_rule() == Rule::integer && var_type.is_integer();
+            if literal_type != var_type && !integer {
+                return Err(format!("Case arm {} is not {:?}", literal.as_span().as_str(), var_type).into());
+            }
+            // Past i64 only an u64 holds the literal, its bits are compared like any other 8 byte value
+            let unsigned = match literal.as_span().as_str().parse::<u64>() {
+                Ok(big) if literal.as_rule() == Rule::integer && big > i64::MAX as u64 && var_type == VarType::U64 && !negative => {
+                    Some(big)
+                }
+                _ => None,
+            };
+            let mut value = match unsigned {
+                Some(big) => big as i64,
+                None => Self::literal_value(&literal)?,
+            };
+            if negative {
+                if literal.as_rule() != Rule::integer {
+                    return Err(format!("Case arm -{} is not a number", literal.as_span().as_str()).into());
+                }
+                value = -value;
+            }
+            if unsigned.is_none() && !var_type.fits(value) {
+                return Err(format!("Case arm {} does not fit in {:?}", value, var_type).into());
+            }
+            if arms.iter().any(|(v, _)| *v == value) {
+                return Err(format!("Duplicate case arm: {}", unsigned.map_or(value.to_string(), |big| big.to_string())).into());
+            }
+            let body = self.parse_body(item.next().unwrap(), code, vars)?;
+            arms.push((value, body));
+        }
+
+        code.statements.push(Statement::Case(Case { value, arms }));
+        Ok(())
+    }
+
+    /// Numeric value of an int, char or bool literal
+    fn literal_value(literal: &Pair<Rule>) -> Result<i64, Box<dyn std::error::Error>> {
+        let text = literal.as_span().as_str();
+        match literal.as_rule() {
+            Rule::integer => text.parse::<i64>().map_err(|_| format!("Integer literal too big: {}", text).into()),
+            Rule::TRUE => Ok(1),
+            Rule::FALSE | Rule::NULL => Ok(0),
+            Rule::char => Self::char_value(text),
+            _ => Err(format!("Not a constant value: {}", text).into()),
+        }
+    }
+
+    /// Byte value of a char literal, decoding the escapes `\n \t \r \\ \' \" \0 \xNN`
+    fn char_value(text: &str) -> Result<i64, Box<dyn std::error::Error>> {
+        let inner = &text[1..text.len() - 1];
+        let byte = match inner.as_bytes() {
+            [c] => *c,
+            [b'\\', b'n'] => b'\n',
+            [b'\\', b't'] => b'\t',
+            [b'\\', b'r'] => b'\r',
+            [b'\\', b'\\'] => b'\\',
+            [b'\\', b'\''] => b'\'',
+            [b'\\', b'"'] => b'"',
+            [b'\\', b'0'] => 0,
+            [b'\\', b'x', _, _] => u8::from_str_radix(&inner[2..], 16)?,
+            _ => return Err(format!("Unsupported char literal: {}", text).into()),
+        };
+        Ok(byte as i64)
+    }
+
+    /// Parses a statement or a block into a new scope
+    fn parse_body(&mut self, pair: Pair<Rule>, code: &Block, vars: &mut VarTree) -> Result<Block, Box<dyn std::error::Error>> {
+        let mut body = self.new_block(code, vars);
+        let mut pair = pair;
+        if pair.as_rule() == Rule::statement && pair.clone().into_inner().next().unwrap().as_rule() == Rule::block {
+            pair = pair.into_inner().next().unwrap();
+        }
+        if pair.as_rule() == Rule::block {
+            for stmt in pair.into_inner() {
+                self.parse_statement(stmt, &mut body, vars)?;
+            }
+        } else {
+            self.parse_statement(pair, &mut body, vars)?;
+        }
+        Ok(body)
+    }
+
+    fn new_block(&mut self, parent: &Block, vars: &mut VarTree) -> Block {
+        let id = self.gen_id();
+        let scope = VarTree {
+            id,
+            father: Some(parent.id),
+            variables: Vec::new(),
+            children: HashMap::new(),
+            stack: 0,
+        };
+        vars.find_tree_mut(parent.id).unwrap().children.insert(id, scope);
+        Block {
+            id,
+            statements: Vec::new(),
+        }
+    }
+
+    /// Variable of the function being parsed visible from the given scope
+    fn find_local(vars: &VarTree, scope: usize, name: &str) -> Option<Variable> {
+        let mut tree = vars.find_tree(scope);
+        while let Some(current) = tree {
+            if let Some(var) = current.variables.iter().find(|v| v.name == name) {
+                return Some(var.clone());
+            }
+            tree = current.father.and_then(|father| vars.find_tree(father));
+        }
+        None
+    }
+
+    /// Reads a variable: a local, a local of the functions enclosing a lambda, which is captured, or a global.
+    /// A declaration shadows the variables with the same name of the enclosing scopes from the point it is
+    /// declared until the end of its block. Declaring the same name twice in a scope is an error.
+    fn variable(&mut self, vars: &VarTree, scope: usize, name: &str) -> Result<Option<Expression>, Box<dyn std::error::Error>> {
+        if let Some(var) = Self::find_local(vars, scope, name) {
+            return Ok(Some(Expression::Variable(var)));
+        }
+        if let Some(capture) = self.capture(self.closures.len(), name)? {
+            return Ok(Some(capture));
+        }
+        Ok(self
+            .variables
+            .variables
+            .iter()
+            .find(|v| v.name == name)
+            .cloned()
+            .map(Expression::Variable))
+    }
+
+    /// Captures a variable for the lambda at `level`, counted from 1 for the outermost one. Lambdas nested in
+    /// other lambdas capture it from the one enclosing them, which captures it in turn.
+    /// Returns how the lambda reads it, `None` when no enclosing function declares it
+    fn capture(&mut self, level: usize, name: &str) -> Result<Option<Expression>, Box<dyn std::error::Error>> {
+        if level == 0 {
+            return Ok(None);
+        }
+        let enclosing = &self.closures[level - 1];
+        if let Some(capture) = enclosing.captures.iter().find(|c| c.name == name) {
+            return Ok(Some(capture.access()));
+        }
+        let source = match Self::find_local(&enclosing.vars, enclosing.scope, name) {
+            Some(var) => Expression::Variable(var),
+            None => match self.capture(level - 1, name)? {
+                Some(source) => source,
+                None => return Ok(None),
+            },
+        };
+        let enclosing = &mut self.closures[level - 1];
+        let by_reference = enclosing.by_reference.iter().any(|n| n == name);
+        if by_reference && !source.is_addressable() {
+            return Err(format!("Cannot capture {} by reference", name).into());
+        }
+        let stored = match by_reference {
+            true => VarType::Pointer(Box::new(source.var_type())),
+            false => source.var_type(),
+        };
+        let offset = enclosing.size.next_multiple_of(stored.align());
+        enclosing.size = offset + stored.size();
+        let capture = Capture {
+            name: name.to_string(),
+            source,
+            by_reference,
+            offset,
+        };
+        let access = capture.access();
+        enclosing.captures.push(capture);
+        Ok(Some(access))
+    }
+
+    /// `[&total, limit] |int x| body` builds a closure, the body being an expression or a block with an optional
+    /// return type. It is compiled as a function of its own, and the locals of the enclosing functions it uses
+    /// are copied to an environment record when the closure is built. Those listed with `&` are captured by
+    /// reference, the record keeps their address, so the closure must not outlive them: returning it from the
+    /// function declaring them is an error, other ways of keeping it, like storing it in a global, are not checked.
+    /// A lambda without captures is a plain function
+    fn parse_lambda(&mut self, pair: Pair<Rule>, code: &Block, vars: &VarTree) -> Result<Expression, Box<dyn std::error::Error>> {
+        let mut inner = pair.into_inner().peekable();
+        let mut listed = Vec::new();
+        if let Some(list) = inner.next_if(|pair| pair.as_rule() == Rule::capture_list) {
+            for capture in list.into_inner() {
+                // The name, after the `&` of a reference
+                let parts: Vec<Pair<Rule>> = capture.into_inner().collect();
+                let name = parts.last().unwrap().as_span().as_str().to_string();
+                if listed.iter().any(|(other, _)| *other == name) {
+                    return Err(format!("Variable captured twice: {}", name).into());
+                }
+                listed.push((name, parts.len() == 2));
+            }
+        }
+        let id = self.gen_id();
+        let name = format!("_lambda{}", id);
+        let parameters = self.parse_parameters(inner.next().unwrap(), id, "lambda")?;
+        let declared = match inner.next_if(|pair| pair.as_rule() == Rule::return_type) {
+            Some(return_type) => Some(self.parse_return_type(return_type, "lambda")?),
+            None => None,
+        };
+        let mut lambda_vars = VarTree {
+            id,
+            father: Some(0),
+            variables: parameters.clone(),
+            children: HashMap::new(),
+            stack: 0,
+        };
+        let mut lambda_code = Block {
+            id,
+            statements: Vec::new(),
+        };
+
+        // The state of the enclosing function is restored even when the body has errors
+        self.closures.push(ClosureScope {
+            vars: vars.clone(),
+            scope: code.id,
+            by_reference: listed
+                .iter()
+                .filter(|(_, by_reference)| *by_reference)
+                .map(|(name, _)| name.clone())
+                .collect(),
+            captures: Vec::new(),
+            size: CLOSURE_HEADER,
+        });
+        let return_type = std::mem::replace(&mut self.return_type, declared.unwrap_or(VarType::Void));
+        let loops = std::mem::take(&mut self.loops);
+        let result = self.lambda_body(&listed, inner.next().unwrap(), &mut lambda_code, &mut lambda_vars);
+        self.loops = loops;
+        self.return_type = return_type;
+        let enclosing = self.closures.pop().unwrap();
+        let return_type = result?;
+
+        if !enclosing.captures.is_empty() {
+            lambda_vars.variables.push(Variable {
+                name: CLOSURE_ENV.to_string(),
+                var_type: VarType::Pointer(Box::new(VarType::Void)),
+                scope: id,
+                stack: None,
+            });
+        }
+        let var_type = VarType::Function(
+            parameters.iter().map(|p| p.var_type.clone()).collect(),
+            Box::new(return_type.clone()),
+        );
+        self.variables.children.insert(id, lambda_vars);
+        let function = Function {
+            name: name.clone(),
+            id,
+            parameters,
+            return_type,
+            code: lambda_code,
+        };
+        self.functions.insert(name.clone(), function);
+        if enclosing.captures.is_empty() {
+            return Ok(Expression::FunctionAddress(name, var_type));
+        }
+        Ok(Expression::Closure(Closure {
+            name,
+            captures: enclosing.captures,
+            size: enclosing.size,
+            var_type,
+        }))
+    }
+
+    /// Parses the body of a lambda, returning its return type
+    fn lambda_body(
+        &mut self,
+        listed: &[(String, bool)],
+        body: Pair<Rule>,
+        code: &mut Block,
+        vars: &mut VarTree,
+    ) -> Result<VarType, Box<dyn std::error::Error>> {
+        // Captured first, even when the body does not use them
+        for (name, _) in listed {
+            if self.capture(self.closures.len(), name)?.is_none() {
+                return Err(format!("Unknown variable in capture list: {}", name).into());
+            }
+        }
+        if body.as_rule() == Rule::block {
+            self.parse_function_body(body, code, vars)?;
+            return Ok(self.return_type.clone());
+        }
+        // An expression is returned, unless it is a call to a void function
+        let stmt = match self.parse_expression(body, code, vars)? {
+            Expression::FunctionCall(call) if call.return_type == VarType::Void => Statement::FunctionCall(call),
+            Expression::ExternFunctionCall(call) if call.return_type == VarType::Void => Statement::ExternFunctionCall(call),
+            value => match value.var_type() {
+                VarType::Null | VarType::Array(_, _) => {
+                    return Err(format!("Cannot return {:?} from a lambda", value.var_type()).into());
+                }
+                _ => {
+                    Self::check_escape(&value)?;
+                    Statement::Return(Some(value))
+                }
+            },
+        };
+        let return_type = match &stmt {
+            Statement::Return(Some(value)) => value.var_type(),
+            _ => VarType::Void,
+        };
+        code.statements.push(stmt);
+        Ok(return_type)
+    }
+
+    fn declaration(&mut self, pair: Pair<Rule>, code: &mut Block, vars: &mut VarTree) -> Result<(), Box<dyn std::error::Error>> {
+        let mut inner = pair.into_inner();
+        let decl_type = inner.next().unwrap();
+        let decl_name = inner.next().unwrap();
+        let name = decl_name.as_span().as_str().to_string();
+        if vars.find_tree(code.id).unwrap().variables.iter().any(|v| v.name == name) {
+            return Err(format!("Variable already declared: {}", name).into());
+        }
+        if decl_type.as_rule() == Rule::LET {
+            return self.let_declaration(inner, code, vars, name);
+        }
+        let var_type = self.parse_type(&decl_type)?;
+        if var_type == VarType::Void || var_type == VarType::VarArgs {
+            return Err(format!("Cannot declare {:?} variable: {}", var_type, name).into());
+        }
+        let var_type = Self::array_type(var_type, &mut inner, &name)?;
+        let var = Variable {
+            name,
+            var_type,
+            scope: code.id,
+            stack: None,
+        };
+        let assign = inner.peek();
+        if assign.is_some() {
+            self.declaration_assignment(inner, code, vars, var.clone())?;
+        } else {
+            code.statements.push(Statement::Declaration(var.clone()));
+        }
+        // Added after the initializer, so it can not refer to the variable itself
+        vars.find_tree_mut(code.id).unwrap().variables.push(var);
+
+        Ok(())
+    }
+
+    /// `let name = value;` declares a variable with the type of its initializer
+    fn let_declaration(
+        &mut self,
+        mut inner: Pairs<Rule>,
+        code: &mut Block,
+        vars: &mut VarTree,
+        name: String,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if Syntax::expect(&inner, Rule::array_size).is_some() {
+            return Err(format!("Cannot infer the size of array {}, declare it with an explicit type", name).into());
+        }
+        // The assignment operator
+        if inner.next().is_none() {
+            return Err(format!("Cannot infer the type of {} without an initializer", name).into());
+        }
+        let value = self.parse_expression(inner.next().unwrap(), code, vars)?;
+        let var_type = value.var_type();
+        match var_type {
+            VarType::Null => return Err(format!("Cannot infer the type of {} from null, declare it with an explicit type", name).into()),
+            VarType::Void => return Err(format!("Cannot declare {:?} variable: {}", var_type, name).into()),
+            VarType::Array(_, _) => {
+                return Err(format!("Cannot infer the type of {} from {:?}, arrays can not be copied", name, var_type).into());
+            }
+            _ => {}
+        }
+        let var = Variable {
+            name,
+            var_type,
+            scope: code.id,
+            stack: None,
+        };
+        code.statements
+            .push(Statement::Assignment(Expression::Variable(var.clone()), Rule::ASSIGN, value));
+        vars.find_tree_mut(code.id).unwrap().variables.push(var);
+        Ok(())
+    }
+
+    /// `let (a, b) = value;` declares a variable for each element of a tuple.
+    /// The tuple is kept in a hidden variable, so the value is evaluated only once
+    fn destructuring(&mut self, pair: Pair<Rule>, code: &mut Block, vars: &mut VarTree) -> Result<(), Box<dyn std::error::Error>> {
+        let inner: Vec<Pair<Rule>> = pair
+            .into_inner()
+            .filter(|pair| matches!(pair.as_rule(), Rule::identifier | Rule::expression))
+            .collect();
+        let (value, names) = inner.split_last().unwrap();
+        let names: Vec<String> = names.iter().map(|name| name.as_span().as_str().to_string()).collect();
+        let value = self.parse_expression(value.clone(), code, vars)?;
+        let var_type = value.var_type();
+        let definition = match &var_type {
+            VarType::Struct(definition) if definition.is_tuple() => definition.clone(),
+            _ => return Err(format!("Cannot destructure {:?}, expected a tuple", var_type).into()),
+        };
+        if definition.fields.len() != names.len() {
+            return Err(format!("Cannot destructure {:?} into {} variables", var_type, names.len()).into());
+        }
+        let scope = vars.find_tree_mut(code.id).unwrap();
+        for (idx, name) in names.iter().enumerate() {
+            if names[..idx].contains(name) || scope.variables.iter().any(|v| v.name == *name) {
+                return Err(format!("Variable already declared: {}", name).into());
+            }
+        }
+        // Not a valid identifier, and unique in the scope
+        let tuple = Variable {
+            name: format!("#tuple{}", scope.variables.len()),
+            var_type,
+            scope: code.id,
+            stack: None,
+        };
+        scope.variables.push(tuple.clone());
+        code.statements
+            .push(Statement::Assignment(Expression::Variable(tuple.clone()), Rule::ASSIGN, value));
+        for (name, field) in names.into_iter().zip(&definition.fields) {
+            let var = Variable {
+                name,
+                var_type: field.var_type.clone(),
+                scope: code.id,
+                stack: None,
+            };
+            let element = Expression::Field(Box::new(Expression::Variable(tuple.clone())), field.name.clone());
+            code.statements
+                .push(Statement::Assignment(Expression::Variable(var.clone()), Rule::ASSIGN, element));
+            scope.variables.push(var);
+        }
+        Ok(())
+    }
+
+    /// Wraps the type in the array sizes following a declared name
+    fn array_type(mut var_type: VarType, inner: &mut Pairs<Rule>, name: &str) -> Result<VarType, Box<dyn std::error::Error>> {
+        let mut lengths = Vec::new();
+        while let Some(size) = Syntax::expect(inner, Rule::array_size) {
+            inner.next();
+            let length: usize = size.into_inner().next().unwrap().as_span().as_str().parse()?;
+            if length == 0 {
+                return Err(format!("Array size must be positive: {}", name).into());
+            }
+            lengths.push(length);
+        }
+        // `int grid[3][4]` is an array of 3 arrays of 4 ints
+        for length in lengths.into_iter().rev() {
+            var_type = VarType::Array(Box::new(var_type), length);
+        }
+        Ok(var_type)
+    }
+
+    fn declaration_assignment(
+        &mut self,
+        mut inner: Pairs<Rule>,
+        code: &mut Block,
+        vars: &mut VarTree,
+        var: Variable,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let ident_type = var.var_type.clone();
+        let assign_type = inner.next().unwrap().as_rule();
+        Self::check_can_assign(&ident_type, assign_type)?;
+        let val = inner.next().unwrap();
+
+        self.assignment_inner(code, vars, Expression::Variable(var), assign_type, val)
+    }
+
+    fn assignment(&mut self, pair: Pair<Rule>, code: &mut Block, vars: &mut VarTree) -> Result<(), Box<dyn std::error::Error>> {
+        let mut inner = pair.into_inner();
+        let target = inner.next().unwrap();
+        let text = target.as_span().as_str().trim().to_string();
+        let target = self.parse_expression(target, code, vars)?;
+        if !target.is_addressable() {
+            return Err(format!("Cannot assign to {}", text).into());
+        }
+        let assign_type = inner.next().unwrap().into_inner().next().unwrap().as_rule();
+        Self::check_can_assign(&target.var_type(), assign_type)?;
+        let val = inner.next().unwrap();
+
+        self.assignment_inner(code, vars, target, assign_type, val)
+    }
+
+    fn assignment_inner(
+        &mut self,
+        code: &mut Block,
+        vars: &mut VarTree,
+        target: Expression,
+        assign_type: Rule,
+        val: Pair<Rule>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut value = self.parse_expression(val, code, vars)?;
+        if let VarType::Pointer(_) = target.var_type()
+            && assign_type != Rule::ASSIGN
+        {
+            // `p += n` moves the pointer by n elements
+            if !VAR_TYPES_MATH.contains(&value.var_type()) {
+                return Err(format!("Pointer offset must be an integer or char, got {:?}", value.var_type()).into());
+            }
+        } else {
+            value = Self::coerce(value, &target.var_type())?;
+            Self::check_can_assign(&value.var_type(), assign_type)?;
+            Self::check_same_type(&target.var_type(), &value.var_type())?;
+        }
+        // if this pass, the types are correct, but we do not check for uninitialized variables
+
+        let mut rule = assign_type;
+        if target.var_type() == VarType::BigInt && rule != Rule::ASSIGN {
+            // `b += x` becomes `b = b + x`, the runtime builds a new bigint
+            let op = match rule {
+                Rule::ASSIGN_PLUS => Rule::PLUS,
+                Rule::ASSIGN_MINUS => Rule::MINUS,
+                Rule::ASSIGN_MULTI => Rule::MULTI,
+                Rule::ASSIGN_DIV => Rule::DIV,
+                _ => Rule::MOD,
+            };
+            value = Self::runtime_operator(target.clone(), op, value)?;
+            rule = Rule::ASSIGN;
+        }
+        let stmt = Statement::Assignment(target, rule, value);
+        code.statements.push(stmt);
+
+        Ok(())
+    }
+
+    /// Builds an expression from a literal or an identifier
+    fn parse_value(&mut self, pair: &Pair<Rule>, code: &Block, vars: &VarTree) -> Result<Expression, Box<dyn std::error::Error>> {
+        match pair.as_rule() {
+            Rule::literal => {
+                let literal = pair.clone().into_inner().next().unwrap();
+                let mut var_type = VarType::from_rule(&literal.as_rule());
+                let mut value = literal.as_span().as_str().to_string();
+                let mut id = None;
+                match var_type {
+                    VarType::String => {
+                        id = Some(self.strings.len());
+                        self.strings.push(value.clone());
+                    }
+                    VarType::Char | VarType::Bool | VarType::Null => {
+                        value = Self::literal_value(&literal)?.to_string();
+                    }
+                    VarType::Float => {
+                        // Kept as the bits of the double, like every other value it is loaded in a general purpose register
+                        value = Self::float_bits(value.parse()?);
+                    }
+                    _ => match value.parse::<i64>() {
+                        // Too big for an int, the literal is an i64 instead
+                        Ok(constant) if !var_type.fits(constant) => var_type = VarType::I64,
+                        Ok(_) => {}
+                        // Past i64 it is an u64, which is not folded as a constant
+                        Err(_) if value.parse::<u64>().is_ok() => var_type = VarType::U64,
+                        // Anything bigger only fits in a bigint, parsed from its digits at runtime
+                        Err(_) => {
+                            let digits = format!("\"{}\"", value);
+                            let id = Some(self.strings.len());
+                            self.strings.push(digits.clone());
+                            let digits = Expression::Value(Parameter {
+                                value: digits,
+                                id,
+                                var_type: VarType::String,
+                            });
+                            return Ok(Self::runtime_call("bigint_parse", vec![digits], VarType::BigInt));
+                        }
+                    },
+                }
+                Ok(Expression::Value(Parameter { value, id, var_type }))
+            }
+            Rule::identifier => {
+                let name = pair.as_span().as_str().to_string();
+                if let Some(variable) = self.variable(vars, code.id, &name)? {
+                    return Ok(variable);
+                }
+                // A function name without a call is its address
+                let signature = match (self.functions.get(&name), self.externs.get(&name)) {
+                    (Some(function), _) => Some((
+                        function.parameters.iter().map(|p| p.var_type.clone()).collect::<Vec<_>>(),
+                        function.return_type.clone(),
+                    )),
+                    (_, Some(function)) => Some((function.parameters.clone(), function.return_type.clone())),
+                    _ => None,
+                };
+                let Some((parameters, return_type)) = signature else {
+                    return Err(format!("Unknown variable: {}", name).into());
+                };
+                if parameters.contains(&VarType::VarArgs) {
+                    return Err(format!("Cannot take the address of variadic function {}", name).into());
+                }
+                let var_type = VarType::Function(parameters, Box::new(return_type));
+                Ok(Expression::FunctionAddress(name, var_type))
+            }
+            _ => panic!("Unknown value: {:?}", pair),
+        }
+    }
+
+    /// Builds an expression tree using precedence climbing, all binary operators are left associative
+    fn parse_expression(&mut self, pair: Pair<Rule>, code: &Block, vars: &VarTree) -> Result<Expression, Box<dyn std::error::Error>> {
+        let mut pairs = pair.into_inner().peekable();
+        self.parse_binary(&mut pairs, 0, code, vars)
+    }
+
+    fn parse_binary(
+        &mut self,
+        pairs: &mut Peekable<Pairs<Rule>>,
+        min_precedence: usize,
+        code: &Block,
+        vars: &VarTree,
+    ) -> Result<Expression, Box<dyn std::error::Error>> {
+        let mut lhs = self.parse_unary(pairs, code, vars)?;
+        while let Some(precedence) = pairs.peek().and_then(Self::precedence) {
+            if precedence < min_precedence {
+                break;
+            }
+            let op = Self::operator(&pairs.next().unwrap());
+            let rhs = self.parse_binary(pairs, precedence + 1, code, vars)?;
+            let (lhs_value, rhs) = Self::unify(lhs, rhs)?;
+            lhs = lhs_value;
+            // Strings and pointers can still be checked against null
+            let null_check = matches!(op, Rule::EQ | Rule::NEQ) && (lhs.is_null() || rhs.is_null()) && lhs.var_type() == rhs.var_type();
+            let runtime = matches!(
+                (lhs.var_type(), rhs.var_type()),
+                (VarType::String, VarType::String) | (VarType::BigInt, VarType::BigInt)
+            );
+            if !null_check && runtime {
+                lhs = Self::runtime_operator(lhs, op, rhs)?;
+                continue;
+            }
+            if !null_check {
+                Self::check_operator(&lhs.var_type(), op, &rhs.var_type())?;
+            }
+            lhs = Expression::Binary(Box::new(lhs), op, Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    /// Operators on the types implemented by the runtime, strings only compare by content while bigints
+    /// also do math. Comparisons test the sign of the difference returned by the runtime
+    fn runtime_operator(lhs: Expression, op: Rule, rhs: Expression) -> Result<Expression, Box<dyn std::error::Error>> {
+        let var_type = lhs.var_type();
+        if matches!(op, Rule::EQ | Rule::NEQ | Rule::GT | Rule::LT | Rule::GTE | Rule::LTE) {
+            let name = if var_type == VarType::String {
+                "string_compare"
+            } else {
+                "bigint_compare"
+            };
+            let compare = Self::runtime_call(name, vec![lhs, rhs], VarType::Int);
+            let zero = Expression::Value(Parameter {
+                value: "0".to_string(),
+                id: None,
+                var_type: VarType::Int,
+            });
+            return Ok(Expression::Binary(Box::new(compare), op, Box::new(zero)));
+        }
+        let name = match (&var_type, op) {
+            (VarType::BigInt, Rule::PLUS) => "bigint_add",
+            (VarType::BigInt, Rule::MINUS) => "bigint_sub",
+            (VarType::BigInt, Rule::MULTI) => "bigint_mul",
+            (VarType::BigInt, Rule::DIV) => "bigint_div",
+            (VarType::BigInt, Rule::MOD) => "bigint_mod",
+            _ => return Err(format!("Cannot use {:?} on {:?}", op, var_type).into()),
+        };
+        Ok(Self::runtime_call(name, vec![lhs, rhs], var_type))
+    }
+
+    fn parse_unary(
+        &mut self,
+        pairs: &mut Peekable<Pairs<Rule>>,
+        code: &Block,
+        vars: &VarTree,
+    ) -> Result<Expression, Box<dyn std::error::Error>> {
+        let pair = pairs.next().unwrap();
+        let term = match pair.as_rule() {
+            Rule::prefix_operator => {
+                let op = Self::operator(&pair);
+                let operand = self.parse_unary(pairs, code, vars)?;
+                let operand_type = operand.var_type();
+                let allowed = match op {
+                    Rule::NOT => operand_type == VarType::Bool,
+                    Rule::DEREF => matches!(&operand_type, VarType::Pointer(target) if **target != VarType::Void),
+                    Rule::ADDRESS => operand.is_addressable(),
+                    _ => operand_type.is_integer() || matches!(operand_type, VarType::Float | VarType::BigInt),
+                };
+                if !allowed {
+                    return Err(format!("Cannot use {:?} on {:?}", op, operand_type).into());
+                }
+                if operand_type == VarType::BigInt {
+                    return Ok(Self::runtime_call("bigint_neg", vec![operand], VarType::BigInt));
+                }
+                // Negative float literals stay literals, so they can initialize globals
+                if let Expression::Value(param) = &operand
+                    && op == Rule::MINUS
+                    && operand_type == VarType::Float
+                {
+                    let value = f64::from_bits(param.value.parse::<i64>()? as u64);
+                    return Ok(Self::float_value(-value));
+                }
+                return Ok(Expression::Unary(op, Box::new(operand)));
+            }
+            Rule::expression => self.parse_expression(pair, code, vars)?,
+            Rule::lambda => self.parse_lambda(pair, code, vars)?,
+            Rule::tuple => {
+                let mut elements = Vec::new();
+                for element in pair.into_inner() {
+                    let element = self.parse_expression(element, code, vars)?;
+                    Self::check_tuple_element(&element.var_type())?;
+                    elements.push(element);
+                }
+                let var_type = VarType::tuple(elements.iter().map(Expression::var_type).collect());
+                Expression::Tuple(elements, var_type)
+            }
+            Rule::function_call => match self.parse_call(pair, code, vars)? {
+                Statement::FunctionCall(call) | Statement::ExternFunctionCall(call) if call.return_type == VarType::Void => {
+                    return Err(format!("Cannot use the result of void function: {}", call.name).into());
+                }
+                Statement::FunctionCall(call) => Expression::FunctionCall(call),
+                Statement::ExternFunctionCall(call) => Expression::ExternFunctionCall(call),
+                stmt => panic!("Expected function call, got: {:?}", stmt),
+            },
+            _ => self.parse_value(&pair, code, vars)?,
+        };
+        self.parse_postfix(term, pairs, code, vars)
+    }
+
+    /// Applies the postfix operators following a term, they bind tighter than the prefix ones
+    fn parse_postfix(
+        &mut self,
+        mut term: Expression,
+        pairs: &mut Peekable<Pairs<Rule>>,
+        code: &Block,
+        vars: &VarTree,
+    ) -> Result<Expression, Box<dyn std::error::Error>> {
+        while let Some(pair) =
+            pairs.next_if(|pair| matches!(pair.as_rule(), Rule::slice | Rule::index | Rule::method | Rule::field | Rule::cast))
+        {
+            term = match pair.as_rule() {
+                Rule::slice => self.parse_slice(term, pair, code, vars)?,
+                Rule::index => self.parse_index(term, pair, code, vars)?,
+                Rule::method => self.parse_method(term, pair, code, vars)?,
+                Rule::cast => self.parse_cast(term, pair)?,
+                _ => Self::parse_field(term, pair)?,
+            };
+        }
+        Ok(term)
+    }
+
+    fn parse_index(
+        &mut self,
+        array: Expression,
+        pair: Pair<Rule>,
+        code: &Block,
+        vars: &VarTree,
+    ) -> Result<Expression, Box<dyn std::error::Error>> {
+        let line = pair.as_span().start_pos().line_col().0;
+        let index = self.parse_expression(pair.into_inner().next().unwrap(), code, vars)?;
+        // The length of a string is only known at runtime
+        let length = match array.var_type() {
+            VarType::Array(_, length) => length as i64,
+            VarType::String | VarType::Vec(_) => i64::MAX,
+            other => return Err(format!("Cannot index {:?}", other).into()),
+        };
+        if !VAR_TYPES_MATH.contains(&index.var_type()) {
+            return Err(format!("Index must be an integer or char, got {:?}", index.var_type()).into());
+        }
+        if let Some(value) = index.constant()
+            && (value < 0 || value >= length)
+        {
+            return Err(format!("Index {} out of bounds for {:?}", value, array.var_type()).into());
+        }
+        Ok(Expression::Index(Box::new(array), Box::new(index), line))
+    }
+
+    /// `s[start..end]` copies the characters from `start` up to, but not including, `end` into a new string
+    fn parse_slice(
+        &mut self,
+        value: Expression,
+        pair: Pair<Rule>,
+        code: &Block,
+        vars: &VarTree,
+    ) -> Result<Expression, Box<dyn std::error::Error>> {
+        if value.var_type() != VarType::String {
+            return Err(format!("Cannot slice {:?}", value.var_type()).into());
+        }
+        let mut parameters = vec![value];
+        for bound in pair.into_inner() {
+            let bound = Self::coerce(self.parse_expression(bound, code, vars)?, &VarType::Int)?;
+            if bound.var_type() != VarType::Int {
+                return Err(format!("Slice bounds must be Int, got {:?}", bound.var_type()).into());
+            }
+            parameters.push(bound);
+        }
+        Ok(Self::runtime_call("string_slice", parameters, VarType::String))
+    }
+
+    /// Methods of the builtin types, implemented by the runtime
+    fn parse_method(
+        &mut self,
+        value: Expression,
+        pair: Pair<Rule>,
+        code: &Block,
+        vars: &VarTree,
+    ) -> Result<Expression, Box<dyn std::error::Error>> {
+        let mut inner = pair.into_inner();
+        let name = inner.next().unwrap().as_span().as_str().to_string();
+        let mut arguments = Vec::new();
+        if let Some(args) = inner.next() {
+            for arg in args.into_inner() {
+                arguments.push(self.parse_expression(arg.into_inner().next().unwrap(), code, vars)?);
+            }
+        }
+        match (value.var_type(), name.as_str()) {
+            (VarType::String, "len") => {
+                Self::check_arguments(&name, &[], &mut arguments)?;
+                Ok(Self::runtime_call("string_len", vec![value], VarType::Int))
+            }
+            (VarType::Vec(_), "len") => {
+                Self::check_arguments(&name, &[], &mut arguments)?;
+                Ok(Self::runtime_call("vec_len", vec![value], VarType::Int))
+            }
+            (VarType::Vec(element), "push") => {
+                // The vec is created on the first push when it is still null, so the place is passed
+                if !value.is_addressable() {
+                    return Err(format!("Cannot push to a temporary {:?}", value.var_type()).into());
+                }
+                Self::check_arguments(&name, std::slice::from_ref(&element), &mut arguments)?;
+                let size = Self::size_value(element.size());
+                let vec = Expression::Unary(Rule::ADDRESS, Box::new(value));
+                Ok(Self::runtime_call("vec_push", vec![vec, size, arguments.remove(0)], VarType::Void))
+            }
+            (VarType::Vec(element), "pop") => {
+                Self::check_arguments(&name, &[], &mut arguments)?;
+                let size = Self::size_value(element.size());
+                Ok(Self::runtime_call("vec_pop", vec![value, size], *element))
+            }
+            (VarType::Map(_, _), "len") => {
+                Self::check_arguments(&name, &[], &mut arguments)?;
+                Ok(Self::runtime_call("map_len", vec![value], VarType::Int))
+            }
+            (VarType::Map(key, entry), "insert") => {
+                // Created on the first insert when it is still null, like a vec
+                if !value.is_addressable() {
+                    return Err(format!("Cannot insert into a temporary {:?}", value.var_type()).into());
+                }
+                Self::check_arguments(&name, &[*key.clone(), *entry.clone()], &mut arguments)?;
+                let map = Expression::Unary(Rule::ADDRESS, Box::new(value));
+                let (key_size, entry_size) = (Self::size_value(key.key_size()), Self::size_value(entry.size()));
+                let mut parameters = vec![map, key_size, entry_size];
+                parameters.append(&mut arguments);
+                Ok(Self::runtime_call("map_insert", parameters, VarType::Void))
+            }
+            (VarType::Map(key, entry), "get") => {
+                // The default is returned for missing keys
+                Self::check_arguments(&name, &[*key, *entry.clone()], &mut arguments)?;
+                let mut parameters = vec![value];
+                parameters.append(&mut arguments);
+                Ok(Self::runtime_call("map_get", parameters, *entry))
+            }
+            (VarType::Map(key, _), "contains") => {
+                Self::check_arguments(&name, &[*key], &mut arguments)?;
+                Ok(Self::runtime_call("map_contains", vec![value, arguments.remove(0)], VarType::Bool))
             }
-            Rule::declaration => self.declaration(pair, code, vars),
-            Rule::assignment => self.assignment(pair, code, vars),
-            _ => {
-                todo!("{:?}", pair)
+            (VarType::Map(key, _), "remove") => {
+                Self::check_arguments(&name, &[*key], &mut arguments)?;
+                Ok(Self::runtime_call("map_remove", vec![value, arguments.remove(0)], VarType::Void))
             }
+            (var_type, _) => Err(format!("{:?} has no method {}", var_type, name).into()),
         }
     }
 
-    fn function_call(&mut self, pair: Pair<Rule>, code: &mut Block, vars: &mut VarTree) -> Result<(), Box<dyn std::error::Error>> {
-        let mut inner = pair.into_inner();
-        let name = inner.next().unwrap().as_span().as_str().to_string();
-        let mut arguments = Vec::new();
-        if let Some(args) = Syntax::expect(&inner, Rule::argument_list) {
-            inner.next();
-            let args = args.into_inner();
-            for arg in args {
-                let arg = arg.into_inner().next().unwrap();
-                let rule = arg.as_rule();
-                match rule {
-                    Rule::literal => {
-                        let arg = arg.into_inner().next().unwrap();
-                        let var_type = VarType::from_rule(&arg.as_rule());
-                        let value = arg.as_span().as_str().to_string();
-                        let id: Option<usize>;
-                        match var_type {
-                            VarType::Int => {
-                                id = None;
-                            }
-                            VarType::Char => {
-                                todo!()
-                            }
-                            VarType::String => {
-                                id = Some(self.strings.len());
-                                self.strings.push(value.clone());
-                            }
-                            VarType::Bool => {
-                                todo!()
-                            }
-                            VarType::Void => {
-                                todo!()
-                            }
-                            VarType::VarArgs => {
-                                todo!()
-                            }
-                        }
-                        let argument = Parameter {
-                            name: "".to_string(),
-                            value: Some(value),
-                            id,
-                            var_type,
-                            is_literal: true,
-                        };
-                        arguments.push(argument);
-                    }
-                    Rule::identifier => {
-                        let name = arg.as_span().as_str().to_string();
-                        let var_info = vars.variables.iter().find(|v| v.name == name.clone());
-                        if var_info.is_none() {
-                            return Err(format!("Unknown variable: {}", name).into());
-                        }
+    /// Size of a type as an argument for the runtime
+    fn size_value(size: usize) -> Expression {
+        Expression::Value(Parameter {
+            value: size.to_string(),
+            id: None,
+            var_type: VarType::I64,
+        })
+    }
 
-                        let argument = Parameter {
-                            name,
-                            value: None,
-                            id: None,
-                            var_type: var_info.unwrap().var_type.clone(),
-                            is_literal: false,
-                        };
-                        arguments.push(argument);
-                    }
-                    _ => {
-                        panic!("Unknown argument: {:?}", arg);
-                    }
-                }
+    /// Call to a function of the runtime linked with every program
+    fn runtime_call(name: &str, parameters: Vec<Expression>, return_type: VarType) -> Expression {
+        Expression::ExternFunctionCall(FnCall {
+            name: name.to_string(),
+            parameters,
+            return_type,
+            callee: None,
+        })
+    }
+
+    /// `value as type` converts between integers, chars and floats, integers are truncated or extended
+    /// and floats are rounded toward zero. Bigints convert from integers, to integers keeping the low
+    /// 64 bits, and to and from decimal strings
+    fn parse_cast(&self, value: Expression, pair: Pair<Rule>) -> Result<Expression, Box<dyn std::error::Error>> {
+        // After the `as` keyword
+        let to = self.parse_type(&pair.into_inner().last().unwrap())?;
+        let from = value.var_type();
+        match (&from, &to) {
+            (VarType::BigInt, VarType::BigInt) => return Ok(value),
+            (VarType::String, VarType::BigInt) => return Ok(Self::runtime_call("bigint_parse", vec![value], to)),
+            (VarType::BigInt, VarType::String) => return Ok(Self::runtime_call("bigint_to_string", vec![value], to)),
+            (_, VarType::BigInt) if from.is_integer() => return Self::coerce(value, &to),
+            (VarType::BigInt, _) if to.is_integer() => {
+                let value = Self::runtime_call("bigint_to_i64", vec![value], VarType::I64);
+                return Ok(if to == VarType::I64 {
+                    value
+                } else {
+                    Expression::Convert(Box::new(value), to)
+                });
             }
+            _ => {}
         }
-        let fn_call = FnCall {
-            name: name.clone(),
-            parameters: arguments,
-        };
-        if self.externs.contains_key(&name) {
-            code.statements.push(Statement::ExternFunctionCall(fn_call));
-            return Ok(());
+        let numeric = |var_type: &VarType| var_type.is_integer() || matches!(var_type, VarType::Char | VarType::Float);
+        if !numeric(&from) || !numeric(&to) {
+            return Err(format!("Cannot convert {:?} to {:?}", from, to).into());
         }
-        if self.functions.contains_key(&name) {
-            code.statements.push(Statement::FunctionCall(fn_call));
-            return Ok(());
+        if from == to {
+            return Ok(value);
         }
-        Err(format!("Unknown function: {}", name).into())
+        Ok(Expression::Convert(Box::new(value), to))
     }
 
-    fn declaration(&mut self, pair: Pair<Rule>, code: &mut Block, vars: &mut VarTree) -> Result<(), Box<dyn std::error::Error>> {
-        let mut inner = pair.into_inner();
-        let decl_type = inner.next().unwrap();
-        let decl_name = inner.next().unwrap();
-        let var_type = VarType::from_rule(&decl_type.as_rule());
-        let name = decl_name.as_span().as_str().to_string();
-        let var = Variable {
-            name,
-            var_type,
-            stack: None,
+    fn parse_field(value: Expression, pair: Pair<Rule>) -> Result<Expression, Box<dyn std::error::Error>> {
+        let name = pair.into_inner().next().unwrap().as_span().as_str().to_string();
+        let VarType::Struct(definition) = value.var_type() else {
+            return Err(format!("Cannot access field {} of {:?}", name, value.var_type()).into());
         };
-        vars.variables.push(var.clone());
-        let assign = inner.peek();
-        if assign.is_some() {
-            self.declaration_assignment(inner, code, vars, var)?;
+        if definition.field(&name).is_none() {
+            let kind = if definition.is_tuple() { "Tuple" } else { "Struct" };
+            return Err(format!("{} {} has no field {}", kind, definition.name, name).into());
         }
+        Ok(Expression::Field(Box::new(value), name))
+    }
 
-        Ok(())
+    /// Brings the operands of a binary operator to the same integer type: a constant takes the type of the other
+    /// side, otherwise the narrower operand is widened. Other mismatches are left for `check_operator`.
+    fn unify(lhs: Expression, rhs: Expression) -> Result<(Expression, Expression), Box<dyn std::error::Error>> {
+        let (lhs_type, rhs_type) = (lhs.var_type(), rhs.var_type());
+        if lhs_type == VarType::Null || (lhs_type.is_integer() && matches!(rhs_type, VarType::Float | VarType::BigInt)) {
+            return Ok((Self::coerce(lhs, &rhs_type)?, rhs));
+        }
+        if rhs_type == VarType::Null || (rhs_type.is_integer() && matches!(lhs_type, VarType::Float | VarType::BigInt)) {
+            return Ok((lhs, Self::coerce(rhs, &lhs_type)?));
+        }
+        if lhs_type == rhs_type || !lhs_type.is_integer() || !rhs_type.is_integer() {
+            return Ok((lhs, rhs));
+        }
+        match (lhs.constant(), rhs.constant()) {
+            (None, Some(_)) => Ok((lhs, Self::coerce(rhs, &lhs_type)?)),
+            (Some(_), None) => Ok((Self::coerce(lhs, &rhs_type)?, rhs)),
+            _ if lhs_type.widens_to(&rhs_type) => Ok((Self::coerce(lhs, &rhs_type)?, rhs)),
+            _ => Ok((lhs, Self::coerce(rhs, &lhs_type)?)),
+        }
     }
 
-    fn declaration_assignment(
-        &mut self,
-        mut inner: Pairs<Rule>,
-        code: &mut Block,
-        vars: &mut VarTree,
-        var: Variable,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let ident_type = var.var_type.clone();
-        let assign_type = inner.next().unwrap().as_rule();
-        Self::check_can_assign(&ident_type, assign_type)?;
-        let val = inner.next().unwrap();
-        let val_type = val.as_rule();
+    /// Converts a value to the type of its destination: `null` takes any pointer or string type, integer
+    /// constants are retyped when they fit, narrower integers are widened and integers become floats.
+    /// Anything else is returned as is, for the caller to report the mismatch.
+    fn coerce(value: Expression, to: &VarType) -> Result<Expression, Box<dyn std::error::Error>> {
+        let from = value.var_type();
+        if from == VarType::Null && matches!(to, VarType::Pointer(_) | VarType::String | VarType::Function(_, _)) {
+            return Ok(Expression::Value(Parameter {
+                value: "0".to_string(),
+                id: None,
+                var_type: to.clone(),
+            }));
+        }
+        // Tuple literals are converted element by element
+        if let (Expression::Tuple(elements, _), VarType::Struct(definition)) = (&value, to)
+            && definition.is_tuple()
+            && definition.fields.len() == elements.len()
+        {
+            let mut converted = Vec::new();
+            for (element, field) in elements.iter().zip(&definition.fields) {
+                converted.push(Self::coerce(element.clone(), &field.var_type)?);
+            }
+            let var_type = VarType::tuple(converted.iter().map(Expression::var_type).collect());
+            return Ok(Expression::Tuple(converted, var_type));
+        }
+        if from.is_integer() && *to == VarType::BigInt {
+            // Built from the 64 bit value, signed or not
+            let (name, wide) = if from.is_signed() {
+                ("bigint_from_i64", VarType::I64)
+            } else {
+                ("bigint_from_u64", VarType::U64)
+            };
+            let value = if from == wide {
+                value
+            } else {
+                Expression::Convert(Box::new(value), wide)
+            };
+            return Ok(Self::runtime_call(name, vec![value], VarType::BigInt));
+        }
+        if from.is_integer() && *to == VarType::Float {
+            return Ok(match value.constant() {
+                Some(constant) => Self::float_value(constant as f64),
+                None => Expression::Convert(Box::new(value), VarType::Float),
+            });
+        }
+        if from == *to || !from.is_integer() || !to.is_integer() {
+            return Ok(value);
+        }
+        if let Some(constant) = value.constant() {
+            if !to.fits(constant) {
+                return Err(format!("Constant {} does not fit in {:?}", constant, to).into());
+            }
+            return Ok(Expression::Value(Parameter {
+                value: constant.to_string(),
+                id: None,
+                var_type: to.clone(),
+            }));
+        }
+        if from.widens_to(to) {
+            return Ok(Expression::Convert(Box::new(value), to.clone()));
+        }
+        Ok(value)
+    }
 
-        self.assignment_inner(code, vars, &var, assign_type, &val, val_type)
+    /// Float literal, stored as the bits of the double
+    fn float_value(value: f64) -> Expression {
+        Expression::Value(Parameter {
+            value: Self::float_bits(value),
+            id: None,
+            var_type: VarType::Float,
+        })
     }
 
-    fn assignment(&mut self, pair: Pair<Rule>, code: &mut Block, vars: &mut VarTree) -> Result<(), Box<dyn std::error::Error>> {
-        let mut inner = pair.into_inner();
-        let ident = inner.next().unwrap().as_span().as_str().to_string();
-        let ident_info = vars.variables.iter().position(|v| v.name == ident.clone());
-        if ident_info.is_none() {
-            return Err(format!("Unknown variable: {}", ident).into());
-        }
-        let ident_info = vars.variables.get(ident_info.unwrap()).unwrap().clone();
-        let ident_type = ident_info.var_type.clone();
-        let assign_type = inner.next().unwrap().into_inner().next().unwrap().as_rule();
-        Self::check_can_assign(&ident_type, assign_type)?;
-        let val = inner.next().unwrap();
-        let val_type = val.as_rule();
+    fn float_bits(value: f64) -> String {
+        (value.to_bits() as i64).to_string()
+    }
 
-        self.assignment_inner(code, vars, &ident_info, assign_type, &val, val_type)
+    /// Binding power of a binary operator, `None` for anything else
+    fn precedence(pair: &Pair<Rule>) -> Option<usize> {
+        let op = Self::operator(pair);
+        PRECEDENCE.iter().position(|level| level.contains(&op))
     }
 
-    fn assignment_inner(
-        &mut self,
-        code: &mut Block,
-        vars: &mut VarTree,
-        ident_info: &Variable,
-        assign_type: Rule,
-        val: &Pair<Rule>,
-        val_type: Rule,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let parameter = match val_type {
-            Rule::literal => {
-                let literal_type = VarType::from_rule(&val.clone().into_inner().next().unwrap().as_rule());
-                Self::check_can_assign(&literal_type, assign_type)?;
-                let id = Some(self.strings.len());
-                let value = val.clone().into_inner().next().unwrap().as_span().as_str().to_string();
-                self.strings.push(value.clone());
-                Parameter {
-                    name: "".to_string(),
-                    value: Some(value),
-                    id,
-                    var_type: literal_type,
-                    is_literal: true,
-                }
+    /// Operator rule of an operator pair
+    fn operator(pair: &Pair<Rule>) -> Rule {
+        match pair.as_rule() {
+            Rule::binary_operator | Rule::comparison_operator | Rule::boolean_operator | Rule::prefix_operator => {
+                pair.clone().into_inner().next().unwrap().as_rule()
             }
-            Rule::identifier => {
-                let name = val.as_span().as_str().to_string();
-                let ident_info2 = vars.variables.iter().find(|v| v.name == name.clone());
-                if ident_info2.is_none() {
-                    return Err(format!("Unknown variable: {}", name).into());
-                }
-                let ident_type2 = ident_info2.unwrap().var_type.clone();
-                Self::check_can_assign(&ident_type2, assign_type)?;
-                todo!()
+            rule => rule,
+        }
+    }
+
+    fn check_operator(lhs: &VarType, op: Rule, rhs: &VarType) -> Result<(), Box<dyn std::error::Error>> {
+        if let VarType::Pointer(target) = lhs {
+            // Offsets and differences count whole elements, so the element size must be known
+            let allowed = match op {
+                Rule::PLUS | Rule::MINUS if VAR_TYPES_MATH.contains(rhs) => target.size() > 0,
+                Rule::MINUS => lhs == rhs && target.size() > 0,
+                Rule::EQ | Rule::NEQ | Rule::GT | Rule::LT | Rule::GTE | Rule::LTE => rhs.converts_to(lhs),
+                _ => false,
+            };
+            if !allowed {
+                return Err(format!("Cannot use {:?} on {:?} and {:?}", op, lhs, rhs).into());
             }
-            _ => {
-                panic!("Unknown value: {:?}", val);
+            return Ok(());
+        }
+        if lhs != rhs {
+            return Err(format!("Mismatched types {:?} {:?} {:?}", lhs, op, rhs).into());
+        }
+        let allowed = match op {
+            Rule::AND | Rule::OR => *lhs == VarType::Bool,
+            Rule::EQ | Rule::NEQ => VAR_TYPES_LOGIC.contains(lhs) || *lhs == VarType::Float,
+            Rule::MOD => VAR_TYPES_MATH.contains(lhs),
+            _ => VAR_TYPES_MATH.contains(lhs) || *lhs == VarType::Float,
+        };
+        if !allowed {
+            return Err(format!("Cannot use {:?} on {:?}", op, lhs).into());
+        }
+        Ok(())
+    }
+
+    fn parse_condition(&mut self, pair: Pair<Rule>, code: &Block, vars: &VarTree) -> Result<Expression, Box<dyn std::error::Error>> {
+        let condition = self.parse_expression(pair, code, vars)?;
+        if condition.var_type() != VarType::Bool {
+            return Err(format!("Condition must be Bool, got {:?}", condition.var_type()).into());
+        }
+        Ok(condition)
+    }
+
+    fn if_statement(&mut self, pair: Pair<Rule>, code: &mut Block, vars: &mut VarTree) -> Result<(), Box<dyn std::error::Error>> {
+        let mut inner = pair.into_inner();
+        let condition = self.parse_condition(inner.next().unwrap(), code, vars)?;
+        let then = self.parse_body(inner.next().unwrap(), code, vars)?;
+        let otherwise = match inner.next() {
+            Some(pair) if pair.as_rule() == Rule::if_statement => {
+                let mut otherwise = self.new_block(code, vars);
+                self.if_statement(pair, &mut otherwise, vars)?;
+                Some(otherwise)
             }
+            Some(pair) => Some(self.parse_body(pair, code, vars)?),
+            None => None,
         };
-        // if this pass, the types are correct, but we do not check for uninitialized variables
+        code.statements.push(Statement::If(If {
+            condition,
+            then,
+            otherwise,
+        }));
+        Ok(())
+    }
 
-        let variable = ident_info.name.clone();
-        let rule = assign_type;
-        let stmt = Statement::Assignment(variable, rule, parameter);
-        code.statements.push(stmt);
+    fn return_statement(&mut self, pair: Pair<Rule>, code: &mut Block, vars: &mut VarTree) -> Result<(), Box<dyn std::error::Error>> {
+        let mut inner = pair.into_inner();
+        let Some(value) = inner.next() else {
+            if self.return_type != VarType::Void {
+                return Err(format!("Missing return value, expected {:?}", self.return_type).into());
+            }
+            code.statements.push(Statement::Return(None));
+            return Ok(());
+        };
+        if self.return_type == VarType::Void {
+            return Err("Cannot return a value from a void function".into());
+        }
+        let value = Self::coerce(self.parse_expression(value, code, vars)?, &self.return_type)?;
+        if value.var_type() != self.return_type {
+            return Err(format!("Cannot return {:?}, expected {:?}", value.var_type(), self.return_type).into());
+        }
+        Self::check_escape(&value)?;
+        code.statements.push(Statement::Return(Some(value)));
+        Ok(())
+    }
 
+    /// A returned closure can not keep the address of a local of the returning function, its frame is gone
+    fn check_escape(value: &Expression) -> Result<(), Box<dyn std::error::Error>> {
+        let Expression::Closure(closure) = value else {
+            return Ok(());
+        };
+        for capture in closure.captures.iter().filter(|capture| capture.by_reference) {
+            // References to captures point to a variable of an enclosing function, globals live forever
+            if let Expression::Variable(var) = &capture.source
+                && var.scope != 0
+            {
+                return Err(format!("Cannot return a closure capturing the local {} by reference", var.name).into());
+            }
+        }
         Ok(())
     }
 
     fn check_can_assign(ident_type: &VarType, assign_type: Rule) -> Result<(), Box<dyn std::error::Error>> {
+        if let VarType::Array(_, _) = ident_type {
+            return Err(format!("Cannot assign {:?} as a whole", ident_type).into());
+        }
         match assign_type {
             Rule::ASSIGN => {
                 // Ok to any kind of assignment
             }
+            Rule::ASSIGN_PLUS | Rule::ASSIGN_MINUS if matches!(ident_type, VarType::Pointer(_)) => {
+                if let VarType::Pointer(target) = ident_type
+                    && target.size() == 0
+                {
+                    return Err(format!("Cannot move {:?}, the element size is unknown", ident_type).into());
+                }
+            }
+            Rule::ASSIGN_PLUS | Rule::ASSIGN_MINUS | Rule::ASSIGN_MULTI | Rule::ASSIGN_DIV if *ident_type == VarType::Float => {
+                // Ok, floats have no remainder
+            }
+            Rule::ASSIGN_PLUS | Rule::ASSIGN_MINUS | Rule::ASSIGN_MULTI | Rule::ASSIGN_DIV | Rule::ASSIGN_MOD
+                if *ident_type == VarType::BigInt =>
+            {
+                // Ok, done by the runtime
+            }
             Rule::ASSIGN_PLUS | Rule::ASSIGN_MINUS | Rule::ASSIGN_MULTI | Rule::ASSIGN_DIV | Rule::ASSIGN_MOD => {
-                if !VAR_TYPES_MATH.contains(&ident_type) {
+                if !VAR_TYPES_MATH.contains(ident_type) {
                     return Err(format!("Cannot perform math on {:?}", ident_type).into());
                 }
             }
             Rule::ASSIGN_AND | Rule::ASSIGN_OR => {
-                if !VAR_TYPES_LOGIC.contains(&ident_type) {
+                if !VAR_TYPES_LOGIC.contains(ident_type) {
                     return Err(format!("Cannot perform logic on {:?}", ident_type).into());
                 }
             }
@@ -342,6 +1777,13 @@ impl<'a> Syntax<'a> {
         Ok(())
     }
 
+    fn check_same_type(ident_type: &VarType, value_type: &VarType) -> Result<(), Box<dyn std::error::Error>> {
+        if !value_type.converts_to(ident_type) {
+            return Err(format!("Cannot assign {:?} to {:?}", value_type, ident_type).into());
+        }
+        Ok(())
+    }
+
     fn parse_extern_function(&mut self, pair: Pair<Rule>) -> Result<(), Box<dyn std::error::Error>> {
         let mut inner = pair.into_inner();
         let name = inner.next().unwrap().as_span().as_str().to_string();
@@ -349,15 +1791,13 @@ impl<'a> Syntax<'a> {
         if let Some(params) = Syntax::expect(&inner, Rule::extern_parameter_list) {
             inner.next();
             for param in params.into_inner() {
-                let param_type = param.as_span().as_str().to_string();
-                parameters.push(VarType::from_str(&param_type));
+                parameters.push(self.parse_type(&param)?);
             }
         }
         let mut return_type = VarType::Void;
         if let Some(rt) = Syntax::expect(&inner, Rule::return_type) {
             inner.next();
-            let rt = rt.into_inner().next().unwrap();
-            return_type = VarType::from_str(rt.as_span().as_str());
+            return_type = self.parse_return_type(rt, &name)?;
         }
         let function = ExternFunction {
             name: name.clone(),
@@ -370,14 +1810,19 @@ impl<'a> Syntax<'a> {
     }
 
     fn expect(rules: &Pairs<'a, Rule>, rule: Rule) -> Option<Pair<'a, Rule>> {
-        if let Some(next) = rules.peek() {
-            if next.as_rule() == rule {
-                return Some(next);
-            }
+        if let Some(next) = rules.peek()
+            && next.as_rule() == rule
+        {
+            return Some(next);
         }
         None
     }
 
+    /// Text of a line of the program, used to report runtime errors
+    pub(crate) fn source_line(&self, line: usize) -> &str {
+        self.content.as_str().lines().nth(line - 1).unwrap_or_default().trim()
+    }
+
     pub fn optimize(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         let mut remove = Vec::new();
         for func in self.functions.values() {
@@ -394,38 +1839,91 @@ impl<'a> Syntax<'a> {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) struct VarTree {
+    pub(crate) id: usize,
     pub(crate) father: Option<usize>,
     pub(crate) variables: Vec<Variable>,
     pub(crate) children: HashMap<usize, VarTree>,
     pub(crate) stack: usize,
 }
 
+impl VarTree {
+    pub(crate) fn find_tree(&self, id: usize) -> Option<&VarTree> {
+        if self.id == id {
+            return Some(self);
+        }
+        self.children.values().find_map(|child| child.find_tree(id))
+    }
+
+    pub(crate) fn find_tree_mut(&mut self, id: usize) -> Option<&mut VarTree> {
+        if self.id == id {
+            return Some(self);
+        }
+        self.children.values_mut().find_map(|child| child.find_tree_mut(id))
+    }
+}
+
+/// Name of the hidden variable holding the position of a `for` loop over a string.
+/// It can not clash with user variables because it is not a valid identifier.
+pub(crate) const FOR_CURSOR: &str = "#cursor";
+/// Position of the element of a vec being visited
+pub(crate) const FOR_INDEX: &str = "#index";
+/// Environment record of a closure, received in %r10
+pub(crate) const CLOSURE_ENV: &str = "#env";
+/// Bytes of the record of a function value before the captured variables: its code and the size of the captures
+const CLOSURE_HEADER: usize = 16;
+
 #[derive(Debug, Clone)]
 pub struct Variable {
     pub(crate) name: String,
-    var_type: VarType,
+    pub(crate) var_type: VarType,
+    /// Id of the VarTree declaring the variable, resolved when the reference is parsed
+    pub(crate) scope: usize,
     pub stack: Option<usize>,
 }
 
+/// A literal value, `id` is the index in the string table for strings.
+/// Pointer and string values without an `id` are null.
 #[derive(Debug, Clone)]
 pub struct Parameter {
-    pub(crate) name: String,
-    pub(crate) value: Option<String>,
+    pub(crate) value: String,
     pub(crate) id: Option<usize>,
     pub(crate) var_type: VarType,
-    pub(crate) is_literal: bool,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum VarType {
+    /// 32 bit signed, also written `i32`
     Int,
+    I8,
+    I16,
+    I64,
+    U8,
+    U16,
+    U32,
+    U64,
     Char,
     String,
     Bool,
     Void,
     VarArgs,
+    /// 64 bit IEEE double, also written `f64`
+    Float,
+    /// Arbitrary precision integer, a pointer to an immutable value built by the runtime, null is 0
+    BigInt,
+    /// Element type and number of elements
+    Array(Box<VarType>, usize),
+    Struct(Rc<Struct>),
+    Pointer(Box<VarType>),
+    /// Growable list, a pointer to a header managed by the runtime
+    Vec(Box<VarType>),
+    /// Hash table from keys to values, managed by the runtime like a vec
+    Map(Box<VarType>, Box<VarType>),
+    /// Function or closure with the given parameter and return types, a pointer to a record starting with its code
+    Function(Vec<VarType>, Box<VarType>),
+    /// Type of `null` until it meets the pointer or string it stands for
+    Null,
 }
 
 impl VarType {
@@ -446,28 +1944,233 @@ impl VarType {
             Rule::string => VarType::String,
             Rule::STRING => VarType::String,
             Rule::char => VarType::Char,
+            Rule::CHAR => VarType::Char,
             Rule::integer => VarType::Int,
-            Rule::INT => VarType::Int,
+            Rule::INT | Rule::I32 => VarType::Int,
+            Rule::I8 => VarType::I8,
+            Rule::I16 => VarType::I16,
+            Rule::I64 => VarType::I64,
+            Rule::U8 => VarType::U8,
+            Rule::U16 => VarType::U16,
+            Rule::U32 => VarType::U32,
+            Rule::U64 => VarType::U64,
+            Rule::BOOL => VarType::Bool,
+            Rule::TRUE => VarType::Bool,
+            Rule::FALSE => VarType::Bool,
+            Rule::NULL => VarType::Null,
+            Rule::VOID => VarType::Void,
+            Rule::VARGS => VarType::VarArgs,
+            Rule::FLOAT | Rule::float => VarType::Float,
+            Rule::BIGINT => VarType::BigInt,
             _ => panic!("Unknown type: {:?}", r),
         }
     }
+
+    /// Bytes taken by a value of the type
+    pub fn size(&self) -> usize {
+        match self {
+            VarType::Char | VarType::Bool | VarType::I8 | VarType::U8 => 1,
+            VarType::I16 | VarType::U16 => 2,
+            VarType::Int | VarType::U32 => 4,
+            VarType::I64
+            | VarType::U64
+            | VarType::Float
+            | VarType::BigInt
+            | VarType::String
+            | VarType::Pointer(_)
+            | VarType::Vec(_)
+            | VarType::Map(_, _)
+            | VarType::Function(_, _)
+            | VarType::Null => 8,
+            VarType::Void | VarType::VarArgs => 0,
+            VarType::Array(element, length) => element.size() * length,
+            VarType::Struct(definition) => definition.size,
+        }
+    }
+
+    pub fn is_integer(&self) -> bool {
+        matches!(
+            self,
+            VarType::Int | VarType::I8 | VarType::I16 | VarType::I64 | VarType::U8 | VarType::U16 | VarType::U32 | VarType::U64
+        )
+    }
+
+    pub fn is_signed(&self) -> bool {
+        matches!(self, VarType::Int | VarType::I8 | VarType::I16 | VarType::I64)
+    }
+
+    /// Whether every value of this integer type can be represented by the other one
+    pub fn widens_to(&self, other: &VarType) -> bool {
+        self.is_integer() && other.is_integer() && other.size() > self.size() && (other.is_signed() || !self.is_signed())
+    }
+
+    /// Whether a constant can be stored in a value of this type
+    pub fn fits(&self, value: i64) -> bool {
+        let bits = self.size() as u32 * 8;
+        if self.is_signed() {
+            bits == 64 || (-(1 << (bits - 1))..1 << (bits - 1)).contains(&value)
+        } else {
+            value >= 0 && (bits == 64 || value < 1 << bits)
+        }
+    }
+
+    /// Value of an integer or char as stored in this type, keeping only its low bits
+    pub fn wrap(&self, value: i64) -> i64 {
+        let bits = self.size() as u32 * 8;
+        if bits == 64 {
+            return value;
+        }
+        let low = value & ((1 << bits) - 1);
+        if self.is_signed() && low >= 1 << (bits - 1) {
+            low - (1 << bits)
+        } else {
+            low
+        }
+    }
+
+    /// Same type, or pointers where one side is `void*`, which stands for any pointer as in C
+    pub fn converts_to(&self, other: &VarType) -> bool {
+        match (self, other) {
+            (VarType::Pointer(from), VarType::Pointer(to)) => from == to || **from == VarType::Void || **to == VarType::Void,
+            // So an aoc function taking typed pointers can be passed where C expects `void*` ones, like to qsort
+            (VarType::Function(from, from_return), VarType::Function(to, to_return)) => {
+                from.len() == to.len() && from.iter().zip(to).all(|(from, to)| from.converts_to(to)) && from_return.converts_to(to_return)
+            }
+            _ => self == other,
+        }
+    }
+
+    /// Tuples are structs without a name, their elements are the fields `0`, `1`...
+    /// Tuples with the same element types are the same type
+    pub fn tuple(elements: Vec<VarType>) -> VarType {
+        let names: Vec<String> = elements.iter().map(|element| format!("{:?}", element)).collect();
+        let name = format!("({})", names.join(", "));
+        let fields = elements
+            .into_iter()
+            .enumerate()
+            .map(|(idx, element)| (idx.to_string(), element))
+            .collect();
+        VarType::Struct(Rc::new(Struct::new(name, fields)))
+    }
+
+    /// Bytes of a map key for the runtime, 0 for strings which are hashed and compared by content
+    pub fn key_size(&self) -> usize {
+        match self {
+            VarType::String => 0,
+            _ => self.size(),
+        }
+    }
+
+    pub fn align(&self) -> usize {
+        match self {
+            VarType::Array(element, _) => element.align(),
+            VarType::Struct(definition) => definition.align,
+            _ => self.size().max(1),
+        }
+    }
+}
+
+/// Layout of a struct, the same C would use so structs can be shared with extern functions
+#[derive(Clone, PartialEq)]
+pub struct Struct {
+    pub(crate) name: String,
+    pub(crate) fields: Vec<Field>,
+    size: usize,
+    align: usize,
+}
+
+impl Struct {
+    /// Same layout as C: every field aligned to its own alignment
+    pub fn new(name: String, fields: Vec<(String, VarType)>) -> Struct {
+        let mut size: usize = 0;
+        let mut align = 1;
+        let fields: Vec<Field> = fields
+            .into_iter()
+            .map(|(name, var_type)| {
+                let offset = size.next_multiple_of(var_type.align());
+                size = offset + var_type.size();
+                align = align.max(var_type.align());
+                Field { name, var_type, offset }
+            })
+            .collect();
+        Struct {
+            name,
+            fields,
+            size: size.next_multiple_of(align),
+            align,
+        }
+    }
+
+    /// Struct names are identifiers, only tuples are named after their element types
+    pub fn is_tuple(&self) -> bool {
+        self.name.starts_with('(')
+    }
+
+    pub fn field(&self, name: &str) -> Option<&Field> {
+        self.fields.iter().find(|f| f.name == name)
+    }
+}
+
+// Only the name, the fields would make type errors unreadable
+impl fmt::Debug for Struct {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub(crate) name: String,
+    pub(crate) var_type: VarType,
+    /// Bytes from the start of the struct
+    pub(crate) offset: usize,
 }
 
-const VAR_TYPES_MATH: [VarType; 2] = [VarType::Int, VarType::Char];
-const VAR_TYPES_LOGIC: [VarType; 3] = [VarType::Bool, VarType::Int, VarType::Char];
+const VAR_TYPES_MATH: [VarType; 9] = [
+    VarType::Int,
+    VarType::I8,
+    VarType::I16,
+    VarType::I64,
+    VarType::U8,
+    VarType::U16,
+    VarType::U32,
+    VarType::U64,
+    VarType::Char,
+];
+const VAR_TYPES_LOGIC: [VarType; 10] = [
+    VarType::Bool,
+    VarType::Int,
+    VarType::I8,
+    VarType::I16,
+    VarType::I64,
+    VarType::U8,
+    VarType::U16,
+    VarType::U32,
+    VarType::U64,
+    VarType::Char,
+];
+/// Binary operators from lowest to highest precedence
+const PRECEDENCE: [&[Rule]; 5] = [
+    &[Rule::OR],
+    &[Rule::AND],
+    &[Rule::EQ, Rule::NEQ, Rule::GT, Rule::LT, Rule::GTE, Rule::LTE],
+    &[Rule::PLUS, Rule::MINUS],
+    &[Rule::MULTI, Rule::DIV, Rule::MOD],
+];
+const VAR_TYPES_CASE: [VarType; 10] = VAR_TYPES_LOGIC;
 
 #[derive(Debug, Clone)]
 pub struct Function {
     pub(crate) name: String,
     pub(crate) id: usize,
-    parameters: HashMap<String, VarType>,
-    return_type: VarType,
+    pub(crate) parameters: Vec<Variable>,
+    pub(crate) return_type: VarType,
     pub(crate) code: Block,
 }
 
 #[derive(Debug, Clone)]
 pub struct Block {
-    id: usize,
+    pub(crate) id: usize,
     pub(crate) statements: Vec<Statement>,
 }
 
@@ -481,7 +2184,219 @@ pub struct ExternFunction {
 #[derive(Debug, Clone)]
 pub struct FnCall {
     pub(crate) name: String,
-    pub(crate) parameters: Vec<Parameter>,
+    pub(crate) parameters: Vec<Expression>,
+    pub(crate) return_type: VarType,
+    /// Function value called instead of the function `name`, which is then the name of the variable
+    pub(crate) callee: Option<Box<Expression>>,
+}
+
+/// Lambda with the variables it captured, `name` is the function made from its body
+#[derive(Debug, Clone)]
+pub struct Closure {
+    pub(crate) name: String,
+    pub(crate) captures: Vec<Capture>,
+    /// Bytes of the environment record, including the header
+    pub(crate) size: usize,
+    pub(crate) var_type: VarType,
+}
+
+#[derive(Debug, Clone)]
+pub struct Capture {
+    name: String,
+    /// Value copied to the record when the closure is built, or the place whose address is kept
+    pub(crate) source: Expression,
+    pub(crate) by_reference: bool,
+    /// Bytes from the start of the record
+    pub(crate) offset: usize,
+}
+
+impl Capture {
+    /// Reads the captured variable inside the lambda, through the address kept for references
+    fn access(&self) -> Expression {
+        let var_type = self.source.var_type();
+        if self.by_reference {
+            let address = Expression::Capture(self.offset, VarType::Pointer(Box::new(var_type)));
+            return Expression::Unary(Rule::DEREF, Box::new(address));
+        }
+        Expression::Capture(self.offset, var_type)
+    }
+}
+
+/// Function enclosing a lambda being parsed, where the variables it captures are looked up
+#[derive(Debug)]
+struct ClosureScope {
+    vars: VarTree,
+    scope: usize,
+    /// Names listed with `&`
+    by_reference: Vec<String>,
+    captures: Vec<Capture>,
+    /// Bytes of the environment record so far
+    size: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct ForLoop {
+    pub(crate) label: Option<String>,
+    pub(crate) variable: Variable,
+    pub(crate) iterable: Expression,
+    pub(crate) code: Block,
+}
+
+#[derive(Debug, Clone)]
+pub struct While {
+    pub(crate) label: Option<String>,
+    pub(crate) condition: Expression,
+    pub(crate) code: Block,
+}
+
+#[derive(Debug, Clone)]
+pub struct Loop {
+    pub(crate) label: Option<String>,
+    pub(crate) code: Block,
+}
+
+#[derive(Debug, Clone)]
+pub struct Case {
+    /// Variable switched on, a capture inside a lambda
+    pub(crate) value: Expression,
+    pub(crate) arms: Vec<(i64, Block)>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Expression {
+    Value(Parameter),
+    Variable(Variable),
+    FunctionCall(FnCall),
+    ExternFunctionCall(FnCall),
+    Unary(Rule, Box<Expression>),
+    Binary(Box<Expression>, Rule, Box<Expression>),
+    /// Array, index and the source line, for the bounds check
+    Index(Box<Expression>, Box<Expression>, usize),
+    /// Struct and field name
+    Field(Box<Expression>, String),
+    /// Number converted to another numeric type, implicitly widened or cast with `as`
+    Convert(Box<Expression>, VarType),
+    /// Tuple literal and its type
+    Tuple(Vec<Expression>, VarType),
+    /// Function used as a value, and its function type
+    FunctionAddress(String, VarType),
+    /// Lambda with captured variables, built when evaluated
+    Closure(Closure),
+    /// Variable captured by the closure being run, at an offset of its environment record
+    Capture(usize, VarType),
+}
+
+impl Expression {
+    pub fn var_type(&self) -> VarType {
+        match self {
+            Expression::Value(param) => param.var_type.clone(),
+            Expression::Variable(var) => var.var_type.clone(),
+            Expression::FunctionCall(call) | Expression::ExternFunctionCall(call) => call.return_type.clone(),
+            Expression::Unary(Rule::ADDRESS, operand) => VarType::Pointer(Box::new(operand.var_type())),
+            Expression::Unary(Rule::DEREF, operand) => match operand.var_type() {
+                VarType::Pointer(target) => *target,
+                other => panic!("Cannot dereference {:?}", other),
+            },
+            Expression::Unary(_, operand) => operand.var_type(),
+            Expression::Binary(lhs, op, rhs) => match op {
+                Rule::AND | Rule::OR | Rule::EQ | Rule::NEQ | Rule::GT | Rule::LT | Rule::GTE | Rule::LTE => VarType::Bool,
+                // Distance between two pointers
+                Rule::MINUS if matches!(rhs.var_type(), VarType::Pointer(_)) => VarType::I64,
+                _ => lhs.var_type(),
+            },
+            Expression::Index(array, _, _) => match array.var_type() {
+                VarType::Array(element, _) => *element,
+                VarType::String => VarType::Char,
+                VarType::Vec(element) => *element,
+                other => panic!("Cannot index {:?}", other),
+            },
+            Expression::Convert(_, var_type)
+            | Expression::Tuple(_, var_type)
+            | Expression::FunctionAddress(_, var_type)
+            | Expression::Capture(_, var_type) => var_type.clone(),
+            Expression::Closure(closure) => closure.var_type.clone(),
+            Expression::Field(value, name) => match value.var_type() {
+                VarType::Struct(definition) => definition.field(name).unwrap().var_type.clone(),
+                other => panic!("Cannot access field {} of {:?}", name, other),
+            },
+        }
+    }
+}
+
+impl Expression {
+    pub fn is_null(&self) -> bool {
+        matches!(self, Expression::Value(param) if param.id.is_none() && matches!(param.var_type, VarType::Pointer(_) | VarType::String | VarType::Function(_, _) | VarType::Null))
+    }
+
+    /// Whether the expression names a place in memory, which can be assigned or pointed to.
+    /// Strings are immutable, their characters can only be read
+    pub fn is_addressable(&self) -> bool {
+        match self {
+            Expression::Index(array, _, _) => array.var_type() != VarType::String,
+            _ => matches!(
+                self,
+                Expression::Variable(_) | Expression::Field(_, _) | Expression::Unary(Rule::DEREF, _)
+            ),
+        }
+    }
+
+    /// Value of an int, char or bool expression made only of literals, floats are never folded
+    pub fn constant(&self) -> Option<i64> {
+        if self.var_type() == VarType::Float {
+            return None;
+        }
+        match self {
+            // Only string literals have an id, everything else is a number
+            Expression::Value(param) => match param.id {
+                Some(_) => None,
+                None => param.value.parse().ok(),
+            },
+            Expression::Variable(_)
+            | Expression::FunctionCall(_)
+            | Expression::ExternFunctionCall(_)
+            | Expression::Index(_, _, _)
+            | Expression::Field(_, _)
+            | Expression::Tuple(_, _)
+            | Expression::FunctionAddress(_, _)
+            | Expression::Closure(_)
+            | Expression::Capture(_, _) => None,
+            Expression::Convert(value, to) => value.constant().map(|value| to.wrap(value)),
+            Expression::Unary(op, operand) => {
+                let value = operand.constant()?;
+                match op {
+                    Rule::MINUS => Some(value.wrapping_neg()),
+                    Rule::NOT => Some(1 - value),
+                    _ => None,
+                }
+            }
+            Expression::Binary(lhs, op, rhs) => {
+                let (lhs, rhs) = (lhs.constant()?, rhs.constant()?);
+                match op {
+                    Rule::PLUS => Some(lhs.wrapping_add(rhs)),
+                    Rule::MINUS => Some(lhs.wrapping_sub(rhs)),
+                    Rule::MULTI => Some(lhs.wrapping_mul(rhs)),
+                    Rule::DIV => lhs.checked_div(rhs),
+                    Rule::MOD => lhs.checked_rem(rhs),
+                    Rule::AND => Some((lhs != 0 && rhs != 0) as i64),
+                    Rule::OR => Some((lhs != 0 || rhs != 0) as i64),
+                    Rule::EQ => Some((lhs == rhs) as i64),
+                    Rule::NEQ => Some((lhs != rhs) as i64),
+                    Rule::GT => Some((lhs > rhs) as i64),
+                    Rule::LT => Some((lhs < rhs) as i64),
+                    Rule::GTE => Some((lhs >= rhs) as i64),
+                    Rule::LTE => Some((lhs <= rhs) as i64),
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct If {
+    pub(crate) condition: Expression,
+    pub(crate) then: Block,
+    pub(crate) otherwise: Option<Block>,
 }
 
 #[derive(Debug, Clone)]
@@ -489,6 +2404,15 @@ pub enum Statement {
     Block(Block),
     FunctionCall(FnCall),
     ExternFunctionCall(FnCall),
-    Return(VarType),
-    Assignment(String, Rule, Parameter),
+    Return(Option<Expression>),
+    /// Declaration without initializer, the variable starts zeroed
+    Declaration(Variable),
+    Assignment(Expression, Rule, Expression),
+    ForLoop(ForLoop),
+    While(While),
+    Loop(Loop),
+    Break(Option<String>),
+    Continue(Option<String>),
+    Case(Case),
+    If(If),
 }